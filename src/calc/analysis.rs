@@ -0,0 +1,182 @@
+/*
+
+Implements a semantic analysis pass, which finds calls with the wrong number of arguments
+and names that are not bound to anything before a program is evaluated.
+
+Top-level forms are checked in order, so a name can only be used after it has been defined.
+Function bodies are evaluated when they are called, so they may use any name the program
+defines, including ones defined after the function. They may even call functions that are
+only defined by a later input, like in mutual recursion, so unknown functions are not reported
+in them. Names bound by `set` and `define` inside
+an expression depend on the order in which it runs, so they count as bound in the whole form.
+
+*/
+
+use std::collections::{HashMap, HashSet};
+
+use super::CalcResult;
+use super::environment::Environment;
+use super::error::{Arity, CalcError};
+use super::expression::{Binding, Builtin, ExprType, Expression};
+use super::function::Function;
+use super::parser::AST;
+use super::statement::StmtType;
+use super::suggest;
+use super::symbol::Symbol;
+
+// The variables and user functions known at some point of the program
+struct Names<'a> {
+    env: &'a Environment,
+    variables: HashSet<Symbol>,
+    functions: HashMap<Symbol, usize>   // The number of parameters of each function
+}
+
+impl<'a> Names<'a> {
+    fn new(env: &'a Environment) -> Names<'a> {
+        Names { env, variables: HashSet::new(), functions: HashMap::new() }
+    }
+
+    fn define(&mut self, ast: &AST) {
+        let mut assigned = vec![];
+        match *ast {
+            AST::Expression(ref e) => e.assigned(&mut assigned),
+            AST::Statement(ref s)  => {
+                s.rhs.assigned(&mut assigned);
+                match s.stmt_type {
+                    StmtType::Assign | StmtType::Define => { self.variables.insert(s.name); }
+                    StmtType::FuncDef                   => { self.functions.insert(s.name, s.params.len()); }
+                }
+            }
+        }
+
+        // The names bound inside expressions may end up in the global scope too
+        self.variables.extend(assigned);
+    }
+
+    fn is_variable(&self, name: Symbol) -> bool {
+        self.variables.contains(&name) || self.env.has_var(name)
+    }
+
+    fn function_params(&self, name: Symbol) -> Option<usize> {
+        self.functions.get(&name).cloned()
+            .or_else(|| self.env.get_fn(name).map(|f| f.params.len()))
+    }
+
+    // Suggests a name the program defines too, even if it isn't in the environment yet
+    fn unknown(&self, name: Symbol, locals: &[Symbol]) -> CalcError {
+        let defined = self.variables.iter().chain(self.functions.keys()).chain(locals);
+        suggest::unknown_name(name.as_str(), self.env, defined.map(|s| s.as_str()))
+    }
+}
+
+// Returns every problem found in the program, without evaluating any of it
+pub fn check(program: &[AST], env: &Environment) -> Vec<CalcError> {
+    let mut global = Names::new(env);
+    for ast in program {
+        global.define(ast);
+    }
+
+    let mut errors = vec![];
+    let mut names = Names::new(env);
+    for ast in program {
+        let mut locals = vec![];
+        match *ast {
+            AST::Expression(ref e) => {
+                e.assigned(&mut locals);
+                check_expr(e, &names, &global, &locals, false, &mut errors);
+            }
+            AST::Statement(ref s)  => {
+                s.rhs.assigned(&mut locals);
+                match s.stmt_type {
+                    StmtType::Assign | StmtType::Define => check_expr(&s.rhs, &names, &global, &locals, false, &mut errors),
+                    StmtType::FuncDef => {
+                        locals.extend(&s.params);
+                        check_expr(&s.rhs, &global, &global, &locals, true, &mut errors);
+                    }
+                }
+            }
+        }
+        names.define(ast);
+    }
+    errors
+}
+
+// Checks an expression, where `locals` are the parameters of the enclosing functions and
+// `let` blocks, and the names bound inside the form. In function bodies, `late` lets calls
+// refer to functions that are not defined yet
+fn check_expr(e: &Expression, names: &Names, global: &Names, locals: &[Symbol], late: bool,
+              errors: &mut Vec<CalcError>)
+{
+    let result = match e.expr_type {
+        ExprType::Operator(op) => op.arity().check(op.symbol(), e.args.len()),
+        ExprType::Function(name, builtin) => check_call(name, builtin, e.args.len(), names, locals, late),
+        ExprType::Variable(name, ref binding) => {
+            if is_bound(name, binding, names, locals) { Ok(()) } else { Err(names.unknown(name, locals)) }
+        }
+        ExprType::Let(ref bound) => {
+            // Each value sees the names bound before it, and the body sees all of them
+            let mut inner = locals.to_vec();
+            for (&name, value) in bound.iter().zip(&e.args) {
+                check_expr(value, names, global, &inner, late, errors);
+                inner.push(name);
+            }
+            for form in &e.args[bound.len()..] {
+                check_expr(form, names, global, &inner, late, errors);
+            }
+            return;
+        }
+        ExprType::Lambda(ref f) => {
+            // The body can also see the parameters of the functions it is created in
+            let mut params = locals.to_vec();
+            params.extend(&f.params);
+            check_expr(&f.body, global, global, &params, true, errors);
+            Ok(())
+        }
+        ExprType::Number(_) | ExprType::Str(_) | ExprType::Matrix(_) | ExprType::Folded(..)
+        | ExprType::Set(_) | ExprType::Define(_) | ExprType::Begin | ExprType::Cond | ExprType::Case => Ok(())
+    };
+
+    if let Err(error) = result {
+        errors.push(error.at(e.span));
+    }
+    for arg in &e.args {
+        check_expr(arg, names, global, locals, late, errors);
+    }
+}
+
+// Follows the same order as the evaluation of a call: local variables, user functions, then
+// global variables holding a function, then builtins
+fn check_call(name: Symbol, builtin: Option<Function>, actual: usize, names: &Names, locals: &[Symbol], late: bool)
+        -> CalcResult<()>
+{
+    // The function a variable holds is only known at runtime, and local variables hide
+    // the user's functions
+    if locals.contains(&name) {
+        return Ok(());
+    }
+
+    if let Some(params) = names.function_params(name) {
+        return Arity::Exactly(params).check(name.as_str(), actual);
+    }
+
+    if names.is_variable(name) {
+        return Ok(());
+    }
+
+    match builtin {
+        Some(f)      => f.arity().check(name.as_str(), actual),
+        None if late => Ok(()),
+        None         => Err(names.unknown(name, locals))
+    }
+}
+
+// Follows the same order as the evaluation of a variable
+fn is_bound(name: Symbol, binding: &Binding, names: &Names, locals: &[Symbol]) -> bool {
+    match *binding {
+        Binding::Global(Builtin::None) => {
+            locals.contains(&name) || names.is_variable(name) || names.function_params(name).is_some()
+        }
+        // Parameters, constants, units and builtin functions
+        _ => true
+    }
+}
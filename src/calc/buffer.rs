@@ -6,12 +6,13 @@ Implements a buffer to be used when reading chars or tokens
 
 pub struct Buffer<T, U> {
     stack: Vec<T>,
-    iterator: U
+    iterator: U,
+    position: usize
 }
 
 impl<T: Copy, U: Iterator<Item=T>> Buffer<T, U> {
     pub fn new(chars: U) -> Buffer<T, U> {
-        Buffer { stack: vec![], iterator: chars }
+        Buffer { stack: vec![], iterator: chars, position: 0 }
     }
 
     pub fn is_empty(&mut self) -> bool {
@@ -40,7 +41,7 @@ impl<T: Copy, U: Iterator<Item=T>> Buffer<T, U> {
     }
 
     pub fn peek(&mut self) -> Option<T> {
-        match self.stack.pop() {
+        match self.pop() {
             Some(c) => {
                 self.push(c);
                 Some(c)
@@ -50,10 +51,20 @@ impl<T: Copy, U: Iterator<Item=T>> Buffer<T, U> {
     }
 
     pub fn pop(&mut self) -> Option<T> {
-        self.stack.pop().or_else(|| self.iterator.next())
+        let elem = self.stack.pop().or_else(|| self.iterator.next());
+        if elem.is_some() {
+            self.position += 1;
+        }
+        elem
     }
 
     pub fn push(&mut self, elem: T) {
+        self.position -= 1;
         self.stack.push(elem);
     }
+
+    // The number of elements that have been consumed
+    pub fn position(&self) -> usize {
+        self.position
+    }
 }
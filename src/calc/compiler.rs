@@ -0,0 +1,353 @@
+/*
+
+Implements the compiler from expressions to the bytecode run by the vm module.
+
+The code of an expression leaves its value on top of a stack. Names carry the builtin meaning
+found by the parser, and the parameters of the function being compiled are read from the slots
+assigned by the resolve module. User-defined variables and functions can still shadow builtins
+at any time, so the vm checks for them first. What the checks find at the top level is cached
+until something new is defined there, see `Guard` and `CallSite`.
+
+Number literals are converted to the numeric mode of the environment they are compiled in, and
+only converted again when they are run in a different one.
+
+*/
+
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::rc::Rc;
+
+use super::expression::{Binding, Builtin, ExprType, Expression};
+use super::function::{Function, UserFunction};
+use super::number::{Number, NumericMode};
+use super::operator::Operator;
+use super::span::Span;
+use super::symbol::Symbol;
+use super::value::Value;
+
+#[derive(Debug)]
+pub enum Op {
+    Number(usize),              // Pushes a number literal, in the numeric mode of the environment
+    Value(usize),               // Pushes a value, like a string
+    Closure(usize),             // Pushes a lambda, which captures the current scope
+    Folded(usize, usize),       // Pushes a folded value and jumps over the original, if its guard holds
+    Local(usize),               // Pushes a parameter of the function being run
+    Name(usize),                // Pushes the value of a name in the table
+    Operator(Operator, usize),  // Applies the operator to the given number of values
+    Call(usize, usize),         // Calls the function of a call site with the given number of values
+    Matrix(usize, usize),       // Builds a matrix with the given number of columns and elements
+    Set(Symbol),                // Pops a value and updates the nearest binding of the name, pushing 0
+    Define(Symbol),             // Pops a value and binds the name in the current scope, pushing 0
+    Scope(usize),               // Runs a nested chunk in a new scope and pushes its value
+    Pop,                        // Drops the value of a form whose result is not used
+
+    // A call to `if` only evaluates the selected branch. If the name has been redefined by the
+    // user, which the guard checks, every argument is evaluated and the user's function is
+    // called instead
+    Branch(usize, usize),       // Pops the condition and jumps to the else branch if it is false
+    SkipElse(usize, usize),     // Jumps over the else branch, at the end of the then branch
+    CallIfRedefined(usize, usize), // Calls the user's function on the condition and both branches
+
+    // Pops an argument of `and` or `or`, and if it settles the result, pushes it and jumps to
+    // the end of the expression
+    ShortCircuit(Operator, usize),
+
+    // The clauses of `cond` and `case` are tried in order, and only the value of the selected
+    // one is evaluated. The key of a `case` stays under the datums until a clause is selected
+    Test(usize),                // Pops a test and jumps to the next clause if it is false
+    Match(usize),               // Pops a datum and the key if they are equal, else jumps to the next clause
+    Jump(usize),                // Jumps to the end, after the value of the selected clause
+    NoClause,                   // Fails when no clause of a `cond` is selected
+    NoMatch                     // Fails when no clause of a `case` is selected, showing the key
+}
+
+#[derive(Debug)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Span>,       // The span of the expression each instruction comes from
+    pub mode: NumericMode,      // The numeric mode the chunk was compiled in
+    pub numbers: Vec<(Number, Value)>, // Each literal, and its value in the mode of the chunk
+    pub values: Vec<Value>,
+    pub functions: Vec<Rc<UserFunction>>,
+    pub scopes: Vec<Chunk>,     // The code of the `let` blocks
+    pub folded: Vec<(Value, NumericMode, usize)>, // Each folded value, its mode and its guard
+    pub guards: Vec<Guard>,
+    pub calls: Vec<CallSite>,
+    pub names: Vec<(Symbol, Builtin)>,
+    pub max_stack: usize,       // The most values the stack can hold, so it is allocated only once
+    pub needs_scope: bool       // Whether the parameters must be bound in a scope, not only in slots
+}
+
+// Checks that none of some names has been defined by the user, so their builtin meaning holds.
+// Local variables are always looked up, since they come and go with every call. Top-level
+// definitions are only looked up again after something new has been defined there
+#[derive(Debug)]
+pub struct Guard {
+    pub names: Vec<Symbol>,
+    pub checked: Cell<Option<u64>> // The version of the environment when the names were free
+}
+
+// A call to a function by name. What it finds at the top level is cached together with the
+// version of the environment at that time
+#[derive(Debug)]
+pub struct CallSite {
+    pub name: Symbol,
+    pub builtin: Option<Function>,
+    pub target: RefCell<Option<(u64, Target)>>
+}
+
+#[derive(Clone, Debug)]
+pub enum Target {
+    User(Rc<UserFunction>),
+    Variable,                   // Looked up on every call, since `set` can change it at any time
+    Builtin
+}
+
+// Compiles the body of a function with the given parameters, or a whole expression, for the
+// given numeric mode
+pub fn compile(e: &Expression, params: &[Symbol], mode: NumericMode) -> Chunk {
+    let mut compiler = Compiler { chunk: Chunk::new(mode), depth: 0, params: params.to_vec() };
+    compiler.expr(e);
+    compiler.chunk
+}
+
+impl Chunk {
+    fn new(mode: NumericMode) -> Chunk {
+        Chunk {
+            code: vec![],
+            spans: vec![],
+            mode,
+            numbers: vec![],
+            values: vec![],
+            functions: vec![],
+            scopes: vec![],
+            folded: vec![],
+            guards: vec![],
+            calls: vec![],
+            names: vec![],
+            max_stack: 0,
+            needs_scope: false
+        }
+    }
+}
+
+struct Compiler {
+    chunk: Chunk,
+    depth: usize,               // The size of the stack after the last instruction
+    params: Vec<Symbol>
+}
+
+impl Compiler {
+    fn expr(&mut self, e: &Expression) {
+        match e.expr_type {
+            ExprType::Number(ref x) => {
+                let value = Value::Number(self.chunk.mode.apply(x.clone()));
+                self.chunk.numbers.push((x.clone(), value));
+                self.emit(Op::Number(self.chunk.numbers.len() - 1), e.span);
+            }
+            ExprType::Str(ref s) => self.value(Value::Str(s.clone()), e.span),
+            ExprType::Lambda(ref f) => {
+                // Lambdas capture the scope, and `set`, `define` and `let` change it
+                self.chunk.needs_scope = true;
+                self.chunk.functions.push(f.clone());
+                self.emit(Op::Closure(self.chunk.functions.len() - 1), e.span);
+            }
+            ExprType::Variable(_, Binding::Local(slot)) => {
+                self.emit(Op::Local(slot), e.span);
+            }
+            ExprType::Variable(name, Binding::Global(ref builtin)) => {
+                let op = Op::Name(self.name(name, builtin));
+                self.emit(op, e.span);
+            }
+            ExprType::Operator(op @ Operator::And) | ExprType::Operator(op @ Operator::Or) => {
+                // Every argument may settle the result, otherwise it is the one of no arguments
+                let mut jumps = vec![];
+                for arg in &e.args {
+                    self.expr(arg);
+                    jumps.push(self.emit(Op::ShortCircuit(op, 0), e.span));
+                }
+                self.value(Value::Boolean(op == Operator::And), e.span);
+                for at in jumps {
+                    self.chunk.code[at] = Op::ShortCircuit(op, self.chunk.code.len());
+                }
+            }
+            ExprType::Operator(op) => {
+                self.args(e);
+                self.emit(Op::Operator(op, e.args.len()), e.span);
+            }
+            ExprType::Function(name, Some(Function::If)) if e.args.len() == 3 => {
+                let guard = self.guard(vec![name]);
+                self.expr(&e.args[0]);
+                let branch = self.emit(Op::Branch(guard, 0), e.span);
+                self.expr(&e.args[1]);
+                let skip = self.emit(Op::SkipElse(guard, 0), e.span);
+                self.chunk.code[branch] = Op::Branch(guard, self.chunk.code.len());
+                self.expr(&e.args[2]);
+                let site = self.call_site(name, Some(Function::If));
+                let end = self.emit(Op::CallIfRedefined(guard, site), e.span) + 1;
+                self.chunk.code[skip] = Op::SkipElse(guard, end);
+            }
+            ExprType::Function(name, builtin) => {
+                self.args(e);
+                let site = self.call_site(name, builtin);
+                self.emit(Op::Call(site, e.args.len()), e.span);
+            }
+            ExprType::Set(name) => {
+                self.chunk.needs_scope = true;
+                self.args(e);
+                self.emit(Op::Set(name), e.span);
+            }
+            ExprType::Define(name) => {
+                self.chunk.needs_scope = true;
+                self.args(e);
+                self.emit(Op::Define(name), e.span);
+            }
+            ExprType::Let(ref names) => {
+                self.chunk.needs_scope = true;
+                let mut scope = Compiler { chunk: Chunk::new(self.chunk.mode), depth: 0, params: vec![] };
+                for (&name, value) in names.iter().zip(&e.args) {
+                    scope.expr(value);
+                    scope.emit(Op::Define(name), e.span);
+                    scope.emit(Op::Pop, e.span);
+                }
+                scope.sequence(&e.args[names.len()..], e.span);
+                self.chunk.scopes.push(scope.chunk);
+                self.emit(Op::Scope(self.chunk.scopes.len() - 1), e.span);
+            }
+            ExprType::Begin => self.sequence(&e.args, e.span),
+            ExprType::Cond => {
+                let depth = self.depth;
+                let ends = self.clauses(&e.args, Op::Test, e.span);
+                match e.args.len() % 2 {
+                    1 => self.expr(e.args.last().unwrap()),
+                    _ => { self.emit(Op::NoClause, e.span); }
+                }
+                self.patch(ends);
+                self.depth = depth + 1;
+            }
+            ExprType::Case => {
+                let depth = self.depth;
+                self.expr(&e.args[0]);
+                let ends = self.clauses(&e.args[1..], Op::Match, e.span);
+                match e.args.len() % 2 {
+                    0 => {
+                        self.emit(Op::Pop, e.span);
+                        self.expr(e.args.last().unwrap());
+                    }
+                    _ => { self.emit(Op::NoMatch, e.span); }
+                }
+                self.patch(ends);
+                self.depth = depth + 1;
+            }
+            ExprType::Matrix(cols) => {
+                self.args(e);
+                self.emit(Op::Matrix(cols, e.args.len()), e.span);
+            }
+            ExprType::Folded(ref value, mode, ref names) => {
+                // The original expression follows, in case the folded value can't be used
+                let guard = self.guard(names.clone());
+                self.chunk.folded.push((value.clone(), mode, guard));
+                let folded = self.chunk.folded.len() - 1;
+                let at = self.emit(Op::Folded(folded, 0), e.span);
+                self.expr(&e.args[0]);
+                self.chunk.code[at] = Op::Folded(folded, self.chunk.code.len());
+            }
+        }
+    }
+
+    fn args(&mut self, e: &Expression) {
+        for arg in &e.args {
+            self.expr(arg);
+        }
+    }
+
+    // Only the value of the last form is kept
+    fn sequence(&mut self, forms: &[Expression], span: Span) {
+        for (i, form) in forms.iter().enumerate() {
+            if i > 0 {
+                self.emit(Op::Pop, span);
+            }
+            self.expr(form);
+        }
+    }
+
+    // Compiles pairs of a test and a value, where `test` checks the first one. Returns the
+    // jumps to the end of the expression
+    fn clauses(&mut self, args: &[Expression], test: fn(usize) -> Op, span: Span) -> Vec<usize> {
+        // Each clause starts with the stack as it was before the first one
+        let depth = self.depth;
+        let mut ends = vec![];
+        for clause in args.chunks_exact(2) {
+            self.depth = depth;
+            self.expr(&clause[0]);
+            let next = self.emit(test(0), span);
+            self.expr(&clause[1]);
+            ends.push(self.emit(Op::Jump(0), span));
+            self.chunk.code[next] = test(self.chunk.code.len());
+        }
+        self.depth = depth;
+        ends
+    }
+
+    fn patch(&mut self, jumps: Vec<usize>) {
+        for at in jumps {
+            self.chunk.code[at] = Op::Jump(self.chunk.code.len());
+        }
+    }
+
+    // Returns the position of the instruction. The size of the stack is tracked as if `if`
+    // had been redefined, since all its arguments are on the stack then
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.depth = match op {
+            Op::Number(_) | Op::Value(_) | Op::Closure(_) | Op::Local(_) | Op::Name(_) => self.depth + 1,
+            Op::Scope(_) | Op::NoClause                                               => self.depth + 1,
+            Op::Pop | Op::ShortCircuit(..) | Op::Test(_) | Op::Match(_)               => self.depth - 1,
+            Op::Operator(_, n) | Op::Call(_, n) | Op::Matrix(_, n)                    => self.depth + 1 - n,
+            Op::CallIfRedefined(..)                                                   => self.depth - 2,
+            Op::Folded(..) | Op::Branch(..) | Op::SkipElse(..) | Op::Jump(_)          => self.depth,
+            Op::NoMatch                                                               => self.depth,
+            Op::Set(_) | Op::Define(_)                                                => self.depth
+        };
+        self.chunk.max_stack = cmp::max(self.chunk.max_stack, self.depth);
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn value(&mut self, value: Value, span: Span) {
+        self.chunk.values.push(value);
+        self.emit(Op::Value(self.chunk.values.len() - 1), span);
+    }
+
+    // Names that are looked up in the scope find the parameters there
+    fn look_up(&mut self, name: Symbol) {
+        if self.params.contains(&name) {
+            self.chunk.needs_scope = true;
+        }
+    }
+
+    fn guard(&mut self, names: Vec<Symbol>) -> usize {
+        for &name in &names {
+            self.look_up(name);
+        }
+        self.chunk.guards.push(Guard { names, checked: Cell::new(None) });
+        self.chunk.guards.len() - 1
+    }
+
+    fn call_site(&mut self, name: Symbol, builtin: Option<Function>) -> usize {
+        self.look_up(name);
+        self.chunk.calls.push(CallSite { name, builtin, target: RefCell::new(None) });
+        self.chunk.calls.len() - 1
+    }
+
+    // Returns the index of the name in the table, adding it if needed
+    fn name(&mut self, name: Symbol, builtin: &Builtin) -> usize {
+        self.look_up(name);
+        if let Some(i) = self.chunk.names.iter().position(|&(n, _)| n == name) {
+            return i;
+        }
+
+        self.chunk.names.push((name, builtin.clone()));
+        self.chunk.names.len() - 1
+    }
+}
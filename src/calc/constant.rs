@@ -6,27 +6,58 @@ Implements the constants of the calculator.
 
 use std::f64;
 
+use num_complex::Complex64;
+
 use super::CalcResult;
+use super::error::CalcError;
+use super::value::Value;
+use super::number::{Number, NumericMode};
+use super::environment::Environment;
+use super::decimal;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Constant {
     Pi,
-    E
+    E,
+    I,
+    True,
+    False
 }
 
 impl Constant {
     pub fn from_str(s: &str) -> CalcResult<Constant> {
-        match s {
-            "pi" => Ok(Constant::Pi),
-            "e"  => Ok(Constant::E),
-            _    => Err(format!("Undefined constant '{}'", s).into())
+        match NAMES.iter().find(|&&(name, _)| name == s) {
+            Some(&(_, c)) => Ok(c),
+            None          => Err(CalcError::unknown_name(s))
         }
     }
 
-    pub fn eval(&self) -> CalcResult {
+    pub fn names() -> impl Iterator<Item=&'static str> {
+        NAMES.iter().map(|&(name, _)| name)
+    }
+
+    pub fn eval(&self, env: &Environment) -> CalcResult {
+        let mode = env.numeric_mode();
         match *self {
-            Constant::Pi => Ok(f64::consts::PI),
-            Constant::E  => Ok(f64::consts::E)
+            Constant::Pi => match mode {
+                NumericMode::Float           => Ok(Value::Number(Number::Float(f64::consts::PI))),
+                NumericMode::Decimal(digits) => Ok(Value::Number(Number::Decimal(decimal::pi(digits))))
+            },
+            Constant::E => match mode {
+                NumericMode::Float           => Ok(Value::Number(Number::Float(f64::consts::E))),
+                NumericMode::Decimal(digits) => Ok(Value::Number(Number::Decimal(decimal::e(digits))))
+            },
+            Constant::I     => Ok(Value::Number(Number::Complex(Complex64::new(0., 1.)))),
+            Constant::True  => Ok(Value::Boolean(true)),
+            Constant::False => Ok(Value::Boolean(false))
         }
     }
 }
+
+static NAMES: [(&str, Constant); 5] = [
+    ("pi",    Constant::Pi),
+    ("e",     Constant::E),
+    ("i",     Constant::I),
+    ("true",  Constant::True),
+    ("false", Constant::False)
+];
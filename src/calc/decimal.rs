@@ -0,0 +1,218 @@
+/*
+
+Implements the arbitrary-precision decimal algorithms used by the decimal
+arithmetic mode. Every function takes the number of significant digits of
+the result, and internally works with a few more digits to absorb rounding
+errors.
+
+*/
+
+use std::f64;
+use std::num::NonZeroU64;
+
+use bigdecimal::{BigDecimal, Context, RoundingMode};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+
+// Extra digits used in intermediate computations
+const GUARD_DIGITS: u64 = 10;
+
+// The most zeros a decimal is padded with when it is printed in positional notation
+const MAX_PLAIN_ZEROS: u64 = 1000;
+
+// Rounds a decimal to the given number of significant digits
+pub fn round(d: &BigDecimal, digits: NonZeroU64) -> BigDecimal {
+    d.with_precision_round(digits, RoundingMode::HalfEven).normalized()
+}
+
+// Converts a float to the decimal with the shortest representation, so `0.1` becomes exactly 0.1
+pub fn from_f64(x: f64) -> Option<BigDecimal> {
+    if x.is_finite() { x.to_string().parse().ok() } else { None }
+}
+
+// Decimals are rationals with a power of ten as denominator, so this conversion is exact
+pub fn to_rational(d: &BigDecimal) -> BigRational {
+    let (digits, scale) = d.as_bigint_and_exponent();
+    if scale >= 0 {
+        BigRational::new(digits, pow10(scale as u64))
+    } else {
+        BigRational::from_integer(digits * pow10(scale.unsigned_abs()))
+    }
+}
+
+// Converts a rational to a decimal, rounding it correctly to the given number of digits
+pub fn from_rational(r: &BigRational, digits: NonZeroU64) -> BigDecimal {
+    let (numer, denom) = (r.numer(), r.denom());
+    if numer.is_zero() {
+        return BigDecimal::zero();
+    }
+
+    // Choose a scale such that the quotient has more digits than needed
+    let scale = (digits.get() + GUARD_DIGITS) as i64 + digit_count(denom) - digit_count(numer);
+    let (quotient, remainder) = if scale >= 0 {
+        (numer * pow10(scale as u64)).div_rem(denom)
+    } else {
+        numer.div_rem(&(denom * pow10(scale.unsigned_abs())))
+    };
+
+    // Append a sticky digit, so a discarded remainder is never mistaken for an exact tie
+    let sticky = if remainder.is_zero() { BigInt::zero() } else { numer.signum() };
+    round(&BigDecimal::new(quotient * 10 + sticky, scale + 1), digits)
+}
+
+// Writes a decimal in positional notation, like `0.000001` instead of `1E-6`. Numbers that
+// would need more zeros than `MAX_PLAIN_ZEROS` keep the scientific notation
+pub fn to_plain(d: &BigDecimal) -> String {
+    let d = d.normalized();
+    let (digits, scale) = d.as_bigint_and_exponent();
+    let zeros = if scale < 0 { scale.unsigned_abs() } else { (scale - digit_count(&digits)).max(0) as u64 };
+    if zeros > MAX_PLAIN_ZEROS {
+        return d.to_string();
+    }
+    if scale <= 0 {
+        return (digits * pow10(scale.unsigned_abs())).to_string();
+    }
+
+    let text = digits.abs().to_string();
+    let scale = scale as usize;
+    let sign = if digits.is_negative() { "-" } else { "" };
+    if text.len() > scale {
+        format!("{}{}.{}", sign, &text[..text.len() - scale], &text[text.len() - scale..])
+    } else {
+        format!("{}0.{}{}", sign, "0".repeat(scale - text.len()), text)
+    }
+}
+
+// Rounds a rational to a fixed number of decimal places. Ties go to the even digit, like
+// when floats are printed with `{:.2}`
+pub fn to_fixed(r: &BigRational, places: usize) -> BigDecimal {
+    let scaled = r * BigRational::from_integer(pow10(places as u64));
+    let floor = scaled.floor();
+    let fraction = &scaled - &floor;
+    let half = BigRational::new(BigInt::from(1), BigInt::from(2));
+
+    let mut digits = floor.to_integer();
+    if fraction > half || (fraction == half && digits.is_odd()) {
+        digits += 1;
+    }
+    BigDecimal::new(digits, places as i64)
+}
+
+pub fn sqrt(d: &BigDecimal, digits: NonZeroU64) -> Option<BigDecimal> {
+    d.sqrt_with_context(&context(digits)).map(|s| round(&s, digits))
+}
+
+pub fn exp(d: &BigDecimal, digits: NonZeroU64) -> BigDecimal {
+    round(&d.exp_with_context(&context(digits)), digits)
+}
+
+// Raises a positive decimal to any power, as `exp(y * ln(x))`
+pub fn pow(x: &BigDecimal, y: &BigDecimal, digits: NonZeroU64) -> BigDecimal {
+    // The error of the logarithm is multiplied by the exponent, so we need more digits
+    // when the result is large
+    let magnitude = (y.to_f64().unwrap_or(0.) * estimate_ln(x)).abs();
+    let extra = if magnitude > 1. { magnitude.log10().ceil() as u64 } else { 0 };
+    let work = with_guard(digits, extra);
+
+    let exponent = ln(x, work) * y;
+    round(&exponent.exp_with_context(&context(work)), digits)
+}
+
+// The natural logarithm of a positive decimal
+pub fn ln(x: &BigDecimal, digits: NonZeroU64) -> BigDecimal {
+    // We write x as m * 2^k, with m close to 1, so ln(x) = ln(m) + k * ln(2)
+    let estimate = estimate_ln(x);
+    let k = (estimate / f64::consts::LN_2).round() as i64;
+
+    // The result is computed in fixed point, so we need extra digits when it is close to 0
+    let extra = if estimate.abs() >= 1. {
+        0
+    } else if estimate == 0. {
+        digits.get()
+    } else {
+        (-estimate.abs().log10()).ceil() as u64
+    };
+    let work = digits.get() + GUARD_DIGITS + extra + digit_count(&BigInt::from(k)) as u64;
+    let unity = pow10(work);
+
+    let two_k = BigRational::from_integer(num_traits::pow(BigInt::from(2), k.unsigned_abs() as usize));
+    let m = if k >= 0 { to_rational(x) / two_k } else { to_rational(x) * two_k };
+    let m_fixed = (m * BigRational::from_integer(unity.clone())).to_integer();
+
+    // ln(m) = 2 * atanh((m - 1) / (m + 1)) and ln(2) = 2 * atanh(1 / 3)
+    let z = (&m_fixed - &unity) * &unity / (&m_fixed + &unity);
+    let ln_m = atanh_fixed(&z, &unity) * 2;
+    let ln_2 = atanh_fixed(&(&unity / 3), &unity) * 2;
+
+    round(&BigDecimal::new(ln_m + ln_2 * k, work as i64), digits)
+}
+
+// Computes pi with Machin's formula: pi = 16 * atan(1/5) - 4 * atan(1/239)
+pub fn pi(digits: NonZeroU64) -> BigDecimal {
+    let work = digits.get() + GUARD_DIGITS;
+    let unity = pow10(work);
+    let pi = atan_inv_fixed(5, &unity) * 16 - atan_inv_fixed(239, &unity) * 4;
+    round(&BigDecimal::new(pi, work as i64), digits)
+}
+
+pub fn e(digits: NonZeroU64) -> BigDecimal {
+    exp(&BigDecimal::from(1), digits)
+}
+
+fn context(digits: NonZeroU64) -> Context {
+    Context::default().with_precision(with_guard(digits, 0))
+}
+
+fn with_guard(digits: NonZeroU64, extra: u64) -> NonZeroU64 {
+    digits.saturating_add(GUARD_DIGITS + extra)
+}
+
+fn pow10(exp: u64) -> BigInt {
+    num_traits::pow(BigInt::from(10), exp as usize)
+}
+
+fn digit_count(i: &BigInt) -> i64 {
+    i.abs().to_string().len() as i64
+}
+
+// A float approximation of ln(x), which also works for decimals that don't fit in a float
+fn estimate_ln(x: &BigDecimal) -> f64 {
+    let (digits, scale) = x.as_bigint_and_exponent();
+    let text = digits.abs().to_string();
+    let lead = &text[..text.len().min(17)];
+    let exp10 = (text.len() - lead.len()) as f64 - scale as f64;
+    lead.parse::<f64>().unwrap_or(1.).ln() + exp10 * f64::consts::LN_10
+}
+
+// atanh(z) = z + z^3/3 + z^5/5 + ..., where z is given in fixed point
+fn atanh_fixed(z: &BigInt, unity: &BigInt) -> BigInt {
+    let z_squared = z * z / unity;
+    let mut power = z.clone();
+    let mut sum = BigInt::zero();
+    let mut n = 1u64;
+    while !power.is_zero() {
+        sum += &power / n;
+        power = power * &z_squared / unity;
+        n += 2;
+    }
+    sum
+}
+
+// atan(1/x) = 1/x - 1/(3x^3) + 1/(5x^5) - ..., in fixed point
+fn atan_inv_fixed(x: u64, unity: &BigInt) -> BigInt {
+    let x_squared = BigInt::from(x * x);
+    let mut power = unity / x;
+    let mut sum = BigInt::zero();
+    let mut n = 1u64;
+    let mut positive = true;
+    while !power.is_zero() {
+        let term = &power / n;
+        if positive { sum += term } else { sum -= term }
+        power /= &x_squared;
+        n += 2;
+        positive = !positive;
+    }
+    sum
+}
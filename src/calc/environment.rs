@@ -3,35 +3,289 @@
 Implements the environment struct, which keeps track of the user-defined variables and functions
 when interpreting multiple commands.
 
+Variables are looked up in a chain of scopes, from the innermost local scope outwards and
+finally in the global one. Calling a function creates a scope for its parameters whose parent
+is the scope the function was created in, so a body sees the names around its definition and
+not the ones of its caller. A `let` block creates a scope inside the one it is evaluated in.
+
 */
 
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::CalcResult;
+use super::error::CalcError;
+use super::function::UserFunction;
+use super::value::Value;
+use super::number::NumericMode;
+use super::symbol::Symbol;
+use super::vm::Evaluator;
 
-use super::function::Function;
+// Deeper recursion is reported as an error instead of overflowing the stack
+const MAX_CALL_DEPTH: usize = 256;
 
+// Not `Clone`, since a copy would share the global variables and functions with the original.
+// The environments of calls and `let` blocks share them on purpose, see `with_scope`
 pub struct Environment {
-    functions: HashMap<String, Function>,
-    variables: HashMap<String, f64>
+    global: Rc<Global>,         // Shared by every environment created from this one
+    scope: Scope,
+    compatibility_mode: bool,
+    numeric_mode: NumericMode,
+    evaluator: Evaluator,
+    call_depth: usize
+}
+
+// Global variables and functions are kept in slots indexed by their symbol, so they are found
+// without hashing their names. Functions can only be defined at the top level
+struct Global {
+    functions: RefCell<Vec<Option<Rc<UserFunction>>>>,
+    variables: RefCell<Vec<Option<Value>>>,
+    version: Cell<u64>          // Renewed by new variables and every function definition
+}
+
+// Versions are never reused, not even by different environments, so a program that is run in
+// several of them can't mistake the version of one for the other
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+// The chain of local scopes, starting from the innermost one. It is empty at the top level.
+// Cloning it shares the scopes, so closures see the updates to the variables they captured
+#[derive(Clone, Default)]
+pub struct Scope(Option<Rc<Frame>>);
+
+// A local scope holds only a few variables, so they are searched in order
+struct Frame {
+    variables: RefCell<Vec<(Symbol, Value)>>,
+    parent: Scope
+}
+
+impl Scope {
+    fn frames(&self) -> impl Iterator<Item=&Frame> {
+        let mut next = self.0.as_deref();
+        iter::from_fn(move || {
+            let frame = next?;
+            next = frame.parent.0.as_deref();
+            Some(frame)
+        })
+    }
+
+    fn get(&self, name: Symbol) -> Option<Value> {
+        self.frames().find_map(|f| f.variables.borrow().iter().find(|b| b.0 == name).map(|b| b.1.clone()))
+    }
+
+    // Updates the nearest binding of the name, or gives the value back if there is none
+    fn set(&self, name: Symbol, value: Value) -> Option<Value> {
+        for frame in self.frames() {
+            if let Some(binding) = frame.variables.borrow_mut().iter_mut().find(|b| b.0 == name) {
+                binding.1 = value;
+                return None;
+            }
+        }
+        Some(value)
+    }
+
+    // Scopes are equal if they are the same objects
+    pub fn ptr_eq(&self, other: &Scope) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None)       => true,
+            _                  => false
+        }
+    }
+}
+
+// The variables are not shown, since they may hold closures that captured the scope itself
+impl fmt::Debug for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Scope")
+    }
 }
 
 impl Environment {
     pub fn new() -> Environment {
-        Environment { functions: HashMap::new(), variables: HashMap::new() }
+        Environment {
+            global: Rc::new(Global {
+                functions: RefCell::new(vec![]),
+                variables: RefCell::new(vec![]),
+                version: Cell::new(next_version())
+            }),
+            scope: Scope::default(),
+            compatibility_mode: false,
+            numeric_mode: NumericMode::Float,
+            evaluator: Evaluator::Bytecode,
+            call_depth: 0
+        }
     }
 
-    pub fn set_var(&mut self, name: &str, value: f64) {
-        self.variables.insert(name.to_string(), value);
+    // Creates the environment in which the body of a user function is evaluated, with the
+    // parameters bound in a new scope inside the one the function was created in. Without
+    // parameters, the body is evaluated in that scope directly
+    pub fn enter_call(&self, scope: &Scope, params: Option<Vec<(Symbol, Value)>>) -> CalcResult<Environment> {
+        if self.call_depth == MAX_CALL_DEPTH {
+            return Err(CalcError::limit_exceeded(
+                format!("Maximum call depth of {} exceeded, check for unbounded recursion", MAX_CALL_DEPTH)));
+        }
+        let scope = match params {
+            Some(params) => Scope(Some(Rc::new(Frame { variables: RefCell::new(params), parent: scope.clone() }))),
+            None         => scope.clone()
+        };
+        Ok(self.with_scope(scope, self.call_depth + 1))
     }
 
-    pub fn get_fn(&self, name: &str) -> Option<Function> {
-        // FIXME: there should be a better way to do this without allocating a String
-        // each time the function is called...
-        self.functions.get(name).map(|&f| f)
+    // Creates the environment of a `let` block, with an empty scope inside the current one
+    pub fn enter_scope(&self) -> Environment {
+        let frame = Frame { variables: RefCell::new(vec![]), parent: self.scope.clone() };
+        self.with_scope(Scope(Some(Rc::new(frame))), self.call_depth)
     }
 
-    pub fn get_var(&self, name: &str) -> Option<f64> {
-        // FIXME: there should be a better way to do this without allocating a String
-        // each time the function is called...
-        self.variables.get(name).map(|&x| x)
+    // Creates an environment with other local scopes, which shares the global variables and
+    // functions of this one and starts with its settings
+    fn with_scope(&self, scope: Scope, call_depth: usize) -> Environment {
+        Environment {
+            global: self.global.clone(),
+            scope,
+            compatibility_mode: self.compatibility_mode,
+            numeric_mode: self.numeric_mode,
+            evaluator: self.evaluator,
+            call_depth
+        }
+    }
+
+    // The local scopes at this point, which the lambdas created here capture
+    pub fn scope(&self) -> Scope {
+        self.scope.clone()
+    }
+
+    // Chooses between floats and decimals with a fixed number of digits for inexact results
+    pub fn set_numeric_mode(&mut self, mode: NumericMode) {
+        self.numeric_mode = mode;
+    }
+
+    pub fn numeric_mode(&self) -> NumericMode {
+        self.numeric_mode
+    }
+
+    // Expressions are compiled to bytecode by default, which is faster than walking them
+    pub fn set_evaluator(&mut self, evaluator: Evaluator) {
+        self.evaluator = evaluator;
+    }
+
+    pub fn evaluator(&self) -> Evaluator {
+        self.evaluator
+    }
+
+    // In compatibility mode numbers are accepted where a boolean is expected,
+    // with 0 meaning false and any other value meaning true
+    pub fn set_compatibility_mode(&mut self, enabled: bool) {
+        self.compatibility_mode = enabled;
+    }
+
+    pub fn compatibility_mode(&self) -> bool {
+        self.compatibility_mode
+    }
+
+    // Updates the nearest scope that binds the name. A name that is not bound anywhere
+    // becomes a global variable
+    pub fn set_var(&self, name: Symbol, value: Value) {
+        if let Some(value) = self.scope.set(name, value) {
+            self.set_global(name, value);
+        }
+    }
+
+    // Binds the name in the innermost scope, hiding any binding of the scopes around it
+    pub fn define_var(&self, name: Symbol, value: Value) {
+        match self.scope.0 {
+            Some(ref frame) => {
+                let mut variables = frame.variables.borrow_mut();
+                match variables.iter_mut().find(|b| b.0 == name) {
+                    Some(binding) => binding.1 = value,
+                    None          => variables.push((name, value))
+                }
+            }
+            None => self.set_global(name, value)
+        }
+    }
+
+    fn set_global(&self, name: Symbol, value: Value) {
+        if slot(&mut self.global.variables.borrow_mut(), name).replace(value).is_none() {
+            self.global.version.set(next_version());
+        }
+    }
+
+    pub fn set_fn(&self, function: Rc<UserFunction>) {
+        let name = function.name;
+        *slot(&mut self.global.functions.borrow_mut(), name) = Some(function);
+        self.global.version.set(next_version());
+    }
+
+    // Changes whenever a name gets a new meaning at the top level, so what was found there
+    // can be cached until then
+    pub fn version(&self) -> u64 {
+        self.global.version.get()
+    }
+
+    pub fn get_fn(&self, name: Symbol) -> Option<Rc<UserFunction>> {
+        self.global.functions.borrow().get(name.index()).and_then(Option::clone)
+    }
+
+    // Only looks in the local scopes, which hide the global variables and functions
+    pub fn get_local(&self, name: Symbol) -> Option<Value> {
+        self.scope.get(name)
+    }
+
+    pub fn get_global(&self, name: Symbol) -> Option<Value> {
+        self.global.variables.borrow().get(name.index()).and_then(Option::clone)
+    }
+
+    pub fn get_var(&self, name: Symbol) -> Option<Value> {
+        self.get_local(name).or_else(|| self.get_global(name))
+    }
+
+    pub fn has_var(&self, name: Symbol) -> bool {
+        self.has_local(name) || matches!(self.global.variables.borrow().get(name.index()), Some(Some(_)))
+    }
+
+    pub fn has_local(&self, name: Symbol) -> bool {
+        self.scope.frames().any(|f| f.variables.borrow().iter().any(|b| b.0 == name))
+    }
+
+    // Whether a user-defined variable or function has the name, which hides any builtin
+    pub fn is_defined(&self, name: Symbol) -> bool {
+        self.has_local(name) || self.is_global(name)
+    }
+
+    // Like `is_defined`, for the top level only
+    pub fn is_global(&self, name: Symbol) -> bool {
+        matches!(self.global.variables.borrow().get(name.index()), Some(Some(_)))
+            || matches!(self.global.functions.borrow().get(name.index()), Some(Some(_)))
+    }
+
+    // The names of the user-defined variables and functions that can be seen from here
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec![];
+        for frame in self.scope.frames() {
+            names.extend(frame.variables.borrow().iter().map(|b| b.0.as_str()));
+        }
+        names.extend(defined(&self.global.variables.borrow()));
+        names.extend(defined(&self.global.functions.borrow()));
+        names
+    }
+}
+
+fn defined<T>(table: &[Option<T>]) -> impl Iterator<Item=&'static str> + '_ {
+    table.iter().enumerate().filter(|&(_, slot)| slot.is_some()).map(|(i, _)| Symbol::from_index(i).as_str())
+}
+
+// Returns the slot of a name, growing the table if it is the first time the name is defined
+fn slot<T>(table: &mut Vec<Option<T>>, name: Symbol) -> &mut Option<T> {
+    if table.len() <= name.index() {
+        table.resize_with(name.index() + 1, || None);
     }
+    &mut table[name.index()]
 }
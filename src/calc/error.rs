@@ -0,0 +1,174 @@
+/*
+
+Implements the error type of the calculator. Errors are classified, so code
+embedding the calculator can tell them apart without looking at messages,
+and know the span of the source that caused them when there is one.
+
+*/
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+
+use super::CalcResult;
+use super::span::Span;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CalcError {
+    // A malformed token, like an invalid number or an unterminated string
+    Lexical { kind: LexicalKind, message: Cow<'static, str>, span: Option<Span> },
+    // Tokens that don't form a valid program
+    Syntax { message: Cow<'static, str>, span: Option<Span> },
+    // A function or operator called with the wrong number of arguments
+    Arity { name: String, expected: Arity, actual: usize, span: Option<Span> },
+    // A name that is not a variable, constant, unit or function, with the known name
+    // that was probably meant
+    UnknownName { name: String, suggestion: Option<String>, span: Option<Span> },
+    // Arguments outside of what an operation accepts, like a list where a number was expected
+    Domain { message: Cow<'static, str>, span: Option<Span> },
+    DivisionByZero { span: Option<Span> },
+    // A computation that would take too many resources, like unbounded recursion
+    LimitExceeded { message: Cow<'static, str>, span: Option<Span> }
+}
+
+impl CalcError {
+    pub fn lexical<M: Into<Cow<'static, str>>>(message: M) -> CalcError {
+        CalcError::Lexical { kind: LexicalKind::Malformed, message: message.into(), span: None }
+    }
+
+    pub fn unterminated<M: Into<Cow<'static, str>>>(message: M) -> CalcError {
+        CalcError::Lexical { kind: LexicalKind::Unterminated, message: message.into(), span: None }
+    }
+
+    pub fn syntax<M: Into<Cow<'static, str>>>(message: M) -> CalcError {
+        CalcError::Syntax { message: message.into(), span: None }
+    }
+
+    pub fn arity(name: &str, expected: Arity, actual: usize) -> CalcError {
+        CalcError::Arity { name: name.to_string(), expected, actual, span: None }
+    }
+
+    pub fn unknown_name(name: &str) -> CalcError {
+        CalcError::UnknownName { name: name.to_string(), suggestion: None, span: None }
+    }
+
+    pub fn domain<M: Into<Cow<'static, str>>>(message: M) -> CalcError {
+        CalcError::Domain { message: message.into(), span: None }
+    }
+
+    pub fn division_by_zero() -> CalcError {
+        CalcError::DivisionByZero { span: None }
+    }
+
+    pub fn limit_exceeded<M: Into<Cow<'static, str>>>(message: M) -> CalcError {
+        CalcError::LimitExceeded { message: message.into(), span: None }
+    }
+
+    pub fn with_suggestion(mut self, name: Option<&str>) -> CalcError {
+        if let CalcError::UnknownName { ref mut suggestion, .. } = self {
+            *suggestion = name.map(str::to_string);
+        }
+        self
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            CalcError::Lexical { span, .. }
+            | CalcError::Syntax { span, .. }
+            | CalcError::Arity { span, .. }
+            | CalcError::UnknownName { span, .. }
+            | CalcError::Domain { span, .. }
+            | CalcError::DivisionByZero { span }
+            | CalcError::LimitExceeded { span, .. } => span
+        }
+    }
+
+    fn span_mut(&mut self) -> &mut Option<Span> {
+        match self {
+            CalcError::Lexical { span, .. }
+            | CalcError::Syntax { span, .. }
+            | CalcError::Arity { span, .. }
+            | CalcError::UnknownName { span, .. }
+            | CalcError::Domain { span, .. }
+            | CalcError::DivisionByZero { span }
+            | CalcError::LimitExceeded { span, .. } => span
+        }
+    }
+
+    // Attaches a span to the error, unless it already has a more precise one
+    pub fn at(mut self, span: Span) -> CalcError {
+        self.span_mut().get_or_insert(span);
+        self
+    }
+
+    // Spans only make sense for the source they were taken from
+    pub fn without_span(mut self) -> CalcError {
+        *self.span_mut() = None;
+        self
+    }
+
+    // Shows the part of the source that caused the error
+    pub fn diagnostic(&self, source: &str) -> Option<String> {
+        self.span().filter(|s| s.end <= source.len()).map(|s| s.render(source))
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::Lexical { message, .. }
+            | CalcError::Syntax { message, .. }
+            | CalcError::Domain { message, .. }
+            | CalcError::LimitExceeded { message, .. } => write!(f, "{}", message),
+            CalcError::Arity { name, expected, actual, .. } => {
+                let verb = if *actual == 1 { "was" } else { "were" };
+                write!(f, "'{}' requires {}, but {} {} given", name, expected, actual, verb)
+            }
+            CalcError::UnknownName { name, suggestion: None, .. } => write!(f, "Unknown name '{}'", name),
+            CalcError::UnknownName { name, suggestion: Some(s), .. } => {
+                write!(f, "Unknown name '{}', did you mean '{}'?", name, s)
+            }
+            CalcError::DivisionByZero { .. }    => write!(f, "Cannot divide by 0")
+        }
+    }
+}
+
+impl error::Error for CalcError {}
+
+// Tells apart the lexical errors that more input could fix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LexicalKind {
+    Malformed,                  // A token that is invalid whatever follows it
+    Unterminated                // A block comment or string that is still open at the end of the input
+}
+
+// The number of arguments accepted by a function or operator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    Between(usize, usize),
+    AtLeast(usize)
+}
+
+impl Arity {
+    pub fn check(self, name: &str, actual: usize) -> CalcResult<()> {
+        let accepted = match self {
+            Arity::Exactly(n)      => actual == n,
+            Arity::Between(lo, hi) => lo <= actual && actual <= hi,
+            Arity::AtLeast(n)      => actual >= n
+        };
+        if accepted { Ok(()) } else { Err(CalcError::arity(name, self, actual)) }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arity::Exactly(1)      => write!(f, "1 argument"),
+            Arity::Exactly(n)      => write!(f, "{} arguments", n),
+            Arity::Between(lo, hi) => write!(f, "{} to {} arguments", lo, hi),
+            Arity::AtLeast(1)      => write!(f, "at least 1 argument"),
+            Arity::AtLeast(n)      => write!(f, "at least {} arguments", n)
+        }
+    }
+}
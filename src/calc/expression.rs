@@ -4,60 +4,259 @@ Implements an expression struct which can be evaluated.
 
 */
 
+use std::iter;
+use std::rc::Rc;
+
 use super::CalcResult;
-use super::environment::Environment;
+use super::environment::{Environment, Scope};
 use super::operator;
 use super::constant::Constant;
-use super::function;
+use super::error::CalcError;
+use super::function::{is_true, Callable, Function, UserFunction};
+use super::value::Value;
+use super::number::{Number, NumericMode};
+use super::units::Quantity;
+use super::matrix::Matrix;
+use super::span::Span;
+use super::suggest;
+use super::symbol::Symbol;
 
 #[derive(Debug)]
 pub enum ExprType {
     Operator(operator::Operator),
-    Function(String),
-    Number(f64),
-    Variable(String)
+    Function(Symbol, Option<Function>), // The name and the builtin it calls if the user doesn't define it
+    Number(Number),
+    Str(String),
+    Variable(Symbol, Binding),
+    Lambda(Rc<UserFunction>),
+    Set(Symbol),                // Updates the nearest binding of the name to the value of the argument
+    Define(Symbol),             // Binds the name in the current scope to the value of the argument
+    Let(Vec<Symbol>),           // The first arguments are the values of the names, the rest is the body
+    Begin,                      // Evaluates the arguments in order
+    Cond,                       // Pairs of a test and a value, and the value of the else clause if any
+    Case,                       // The key, pairs of a datum and a value, and the value of the else clause if any
+    Matrix(usize),              // The number of columns. The elements are the arguments, row by row
+    Folded(Value, NumericMode, Vec<Symbol>) // A value computed ahead of time, see the optimize module
+}
+
+impl ExprType {
+    // The builtin meaning of a name is found once, when it is parsed
+    pub fn function(name: Symbol) -> ExprType {
+        ExprType::Function(name, Function::from_str(name.as_str()).ok())
+    }
+
+    pub fn variable(name: Symbol) -> ExprType {
+        ExprType::Variable(name, Binding::Global(Builtin::of(name)))
+    }
+}
+
+// Where the value of a variable comes from
+#[derive(Clone, Debug)]
+pub enum Binding {
+    Local(usize),       // A parameter of the enclosing function, by position. See the resolve module
+    Global(Builtin)     // A user-defined variable, or else the builtin meaning of the name
 }
 
+// What a name means when it is used as a value and the user hasn't defined it
+#[derive(Clone, Debug)]
+pub enum Builtin {
+    Constant(Constant),
+    Unit(Quantity),
+    Function(Function),
+    None
+}
+
+impl Builtin {
+    pub fn of(name: Symbol) -> Builtin {
+        let name = name.as_str();
+        if let Ok(c) = Constant::from_str(name) {
+            Builtin::Constant(c)
+        } else if let Some(q) = Quantity::from_unit(name) {
+            Builtin::Unit(q)
+        } else if let Ok(f) = Function::from_str(name) {
+            Builtin::Function(f)
+        } else {
+            Builtin::None
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Expression {
     pub expr_type: ExprType,
-    pub args: Vec<Expression>
+    pub args: Vec<Expression>,
+    pub span: Span
 }
 
 impl Expression {
-    pub fn from_type(ty: ExprType) -> Expression {
-        Expression { expr_type: ty, args: vec![] }
+    pub fn from_type(ty: ExprType, span: Span) -> Expression {
+        Expression { expr_type: ty, args: vec![], span }
     }
 
+    // Collects the names bound by `set` and `define` anywhere in the expression, including
+    // the bodies of the lambdas in it
+    pub fn assigned(&self, names: &mut Vec<Symbol>) {
+        match self.expr_type {
+            ExprType::Set(name) | ExprType::Define(name) => names.push(name),
+            ExprType::Lambda(ref f) => f.body.assigned(names),
+            _ => ()
+        }
+        for arg in &self.args {
+            arg.assigned(names);
+        }
+    }
+
+    // Errors get the span of the innermost expression that caused them
     pub fn eval(&self, env: &Environment) -> CalcResult {
+        self.eval_inner(env).map_err(|e| e.at(self.span))
+    }
+
+    fn eval_inner(&self, env: &Environment) -> CalcResult {
         match self.expr_type {
             ExprType::Operator(op) => {
                 op.eval(&self.args, env)
             }
-            ExprType::Function(ref name) => {
-                // Check if the function has been defined by the user
-                match env.get_fn(name) {
-                    Some(f) => return f.eval(&self.args, env),
-                    None    => ()
+            ExprType::Function(name, builtin) => {
+                // Local variables hide everything else. Then check if the function has been
+                // defined by the user
+                let local = env.get_local(name);
+                if local.is_none() {
+                    if let Some(f) = env.get_fn(name) {
+                        return f.eval(&self.args, env);
+                    }
+                }
+
+                // Or if it is a variable holding a function
+                if let Some(v) = local.or_else(|| env.get_global(name)) {
+                    let f = v.into_callable()?;
+                    let values = self.args.iter().map(|a| a.eval(env)).collect::<CalcResult<Vec<_>>>()?;
+                    return f.call(values, env);
                 }
 
                 // Otherwise, treat it as a predefined function
-                function::Function::from_str(name)
-                    .and_then(|f| f.eval(&self.args, env))
+                match builtin {
+                    Some(f) => f.eval(&self.args, env),
+                    None    => Err(suggest::unknown_name(name.as_str(), env, iter::empty()))
+                }
+            }
+            ExprType::Number(ref x) => {
+                Ok(Value::Number(env.numeric_mode().apply(x.clone())))
+            }
+            ExprType::Str(ref s) => {
+                Ok(Value::Str(s.clone()))
+            }
+            ExprType::Variable(name, Binding::Global(ref builtin)) => load(name, builtin, env),
+            // Parameters are variables of the scope the body is evaluated in too
+            ExprType::Variable(name, Binding::Local(_)) => load(name, &Builtin::None, env),
+            ExprType::Lambda(ref f) => {
+                Ok(Value::Function(Callable::User(f.clone(), env.scope())))
+            }
+            ExprType::Set(name) => {
+                env.set_var(name, self.args[0].eval(env)?);
+                Ok(Value::Number(Number::from(0)))
             }
-            ExprType::Number(x) => {
-                Ok(x)
+            ExprType::Define(name) => {
+                env.define_var(name, self.args[0].eval(env)?);
+                Ok(Value::Number(Number::from(0)))
             }
-            ExprType::Variable(ref name) => {
-                // Check if the variable has been defined by the user
-                match env.get_var(name) {
-                    Some(v) => return Ok(v),
-                    None    => ()
+            ExprType::Let(ref names) => {
+                // Each value can use the names bound before it
+                let local = env.enter_scope();
+                for (&name, value) in names.iter().zip(&self.args) {
+                    local.define_var(name, value.eval(&local)?);
                 }
+                eval_sequence(&self.args[names.len()..], &local)
+            }
+            ExprType::Begin => eval_sequence(&self.args, env),
+            ExprType::Cond => self.eval_cond(env),
+            ExprType::Case => self.eval_case(env),
+            ExprType::Folded(ref value, mode, ref names) => {
+                // The value is only valid while the names it uses keep their builtin meaning
+                if mode == env.numeric_mode() && !names.iter().any(|&name| env.is_defined(name)) {
+                    Ok(value.clone())
+                } else {
+                    self.args[0].eval(env)
+                }
+            }
+            ExprType::Matrix(cols) => {
+                let elements = self.args.iter()
+                                        .map(|a| a.eval(env).and_then(Value::into_number))
+                                        .collect::<CalcResult<Vec<_>>>()?;
+                Ok(Value::Matrix(Matrix::new(elements.len() / cols, cols, elements)))
+            }
+        }
+    }
+}
 
-                // Otherwise, treat it as a constant
-                Constant::from_str(name)
-                    .and_then(|c| c.eval())
+// The clauses are kept out of `eval_inner`, so recursive calls use less stack
+impl Expression {
+    // Only the value of the first clause whose test holds is evaluated
+    fn eval_cond(&self, env: &Environment) -> CalcResult {
+        let mut clauses = self.args.chunks_exact(2);
+        for clause in &mut clauses {
+            if is_true(clause[0].eval(env)?, env)? {
+                return clause[1].eval(env);
             }
         }
+        match clauses.remainder().first() {
+            Some(value) => value.eval(env),
+            None        => Err(no_clause(None))
+        }
+    }
+
+    // The key is evaluated once, and the datums only until one of them is equal to it
+    fn eval_case(&self, env: &Environment) -> CalcResult {
+        let key = self.args[0].eval(env)?;
+        let mut clauses = self.args[1..].chunks_exact(2);
+        for clause in &mut clauses {
+            if clause[0].eval(env)? == key {
+                return clause[1].eval(env);
+            }
+        }
+        match clauses.remainder().first() {
+            Some(value) => value.eval(env),
+            None        => Err(no_clause(Some(&key)))
+        }
+    }
+}
+
+// Evaluates the forms in order and returns the value of the last one
+fn eval_sequence(forms: &[Expression], env: &Environment) -> CalcResult {
+    let (last, rest) = forms.split_last().unwrap();
+    for form in rest {
+        form.eval(env)?;
+    }
+    last.eval(env)
+}
+
+// The error of a `cond` or `case` without an else clause when none of its clauses is selected
+pub fn no_clause(key: Option<&Value>) -> CalcError {
+    match key {
+        Some(key) => CalcError::domain(format!("No clause of the case matches the value {}", key)),
+        None      => CalcError::domain("No clause of the cond has a true test")
+    }
+}
+
+// Finds the value of a variable. The bytecode follows the same order
+pub fn load(name: Symbol, builtin: &Builtin, env: &Environment) -> CalcResult {
+    // Check if the variable has been defined by the user
+    if let Some(v) = env.get_var(name) {
+        return Ok(v);
+    }
+
+    // Otherwise, treat it as a constant or a unit
+    match *builtin {
+        Builtin::Constant(ref c) => return c.eval(env),
+        Builtin::Unit(ref q)     => return Ok(q.clone().into_value(env.numeric_mode())),
+        _                        => ()
+    }
+
+    // Functions can be used as values, like in `(map sqrt (list 1 4 9))`
+    if let Some(f) = env.get_fn(name) {
+        return Ok(Value::Function(Callable::User(f, Scope::default())));
+    }
+    match *builtin {
+        Builtin::Function(f) => Ok(Value::Function(Callable::Builtin(f))),
+        _                    => Err(suggest::unknown_name(name.as_str(), env, iter::empty()))
     }
 }
@@ -0,0 +1,141 @@
+/*
+
+Implements the templates of the `format` builtin. Placeholders are written
+like in Rust: `{}` inserts the next argument, `{:8}` pads it to a width of 8,
+`{:.2}` prints numbers with two decimal places and `{:<8.2}` does both with
+an explicit alignment. Braces are escaped by doubling them.
+
+*/
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use super::CalcResult;
+use super::error::CalcError;
+use super::value::Value;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center
+}
+
+struct Spec {
+    align: Option<Align>,
+    width: usize,
+    precision: Option<usize>
+}
+
+pub fn format(template: &str, args: Vec<Value>) -> CalcResult<String> {
+    let mut result = String::new();
+    let mut args = args.into_iter();
+    let mut placeholders = 0;
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let spec = parse_spec(&mut chars)?;
+                placeholders += 1;
+                match args.next() {
+                    Some(value) => result.push_str(&apply_spec(&spec, &value)),
+                    None        => {
+                        return Err(CalcError::domain(format!("The format string has more placeholders than the {} arguments given",
+                                           placeholders - 1)));
+                    }
+                }
+            }
+            '}' => return Err(CalcError::domain("Unmatched '}' in format string, use '}}' to insert a brace")),
+            c   => result.push(c)
+        }
+    }
+
+    let unused = args.count();
+    if unused > 0 {
+        return Err(CalcError::domain(format!("The format string has {} placeholders, but {} arguments were given",
+                           placeholders, placeholders + unused)));
+    }
+    Ok(result)
+}
+
+// Parses a placeholder after its opening brace, up to and including its closing brace
+fn parse_spec(chars: &mut Peekable<Chars>) -> CalcResult<Spec> {
+    let mut spec = Spec { align: None, width: 0, precision: None };
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c)   => text.push(c),
+            None      => return Err(CalcError::domain(format!("Unterminated placeholder '{{{}' in format string", text)))
+        }
+    }
+    let body = match text.strip_prefix(':') {
+        Some(body)                => body,
+        None if text.is_empty()   => return Ok(spec),
+        None                      => return Err(CalcError::domain(format!("Invalid placeholder '{{{}}}' in format string", text)))
+    };
+
+    let invalid = || CalcError::domain(format!("Invalid format specifier '{{{}}}'", text));
+    let mut rest = body;
+    spec.align = match rest.chars().next() {
+        Some('<') => Some(Align::Left),
+        Some('>') => Some(Align::Right),
+        Some('^') => Some(Align::Center),
+        _         => None
+    };
+    if spec.align.is_some() {
+        rest = &rest[1..];
+    }
+
+    let (width, precision) = match rest.find('.') {
+        Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+        None    => (rest, None)
+    };
+    if !width.is_empty() {
+        spec.width = width.parse().map_err(|_| invalid())?;
+        // The padding is allocated up front, so the width is bounded like the precision
+        if spec.width > u16::MAX as usize {
+            return Err(CalcError::domain(format!("The width in '{{{}}}' can be at most {}", text, u16::MAX)));
+        }
+    }
+    if let Some(precision) = precision {
+        let precision = precision.parse().map_err(|_| invalid())?;
+        // Rust's formatting machinery panics on precisions that don't fit in a u16
+        if precision > u16::MAX as usize {
+            return Err(CalcError::domain(format!("The precision in '{{{}}}' can be at most {}", text, u16::MAX)));
+        }
+        spec.precision = Some(precision);
+    }
+
+    Ok(spec)
+}
+
+fn apply_spec(spec: &Spec, value: &Value) -> String {
+    let text = match spec.precision {
+        Some(p) => format!("{:.*}", p, value),
+        None    => value.to_string()
+    };
+
+    // Like in Rust, numbers are aligned to the right by default and everything else to the left
+    let align = spec.align.unwrap_or(match *value {
+        Value::Number(_) | Value::Quantity(_) => Align::Right,
+        _                                     => Align::Left
+    });
+
+    let padding = spec.width.saturating_sub(text.chars().count());
+    let (before, after) = match align {
+        Align::Left   => (0, padding),
+        Align::Right  => (padding, 0),
+        Align::Center => (padding / 2, padding - padding / 2)
+    };
+    format!("{}{}{}", " ".repeat(before), text, " ".repeat(after))
+}
@@ -4,60 +4,412 @@ Implements the functions of the calculator.
 
 */
 
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
 use super::CalcResult;
+use super::error::{Arity, CalcError};
 use super::expression::Expression;
-use super::environment::Environment;
+use super::environment::{Environment, Scope};
+use super::value::Value;
+use super::number::{Number, NumericMode};
+use super::units::Quantity;
+use super::matrix::Matrix;
+use super::format;
+use super::compiler::{self, Chunk};
+use super::vm::{self, Evaluator};
+use super::resolve;
+use super::symbol::Symbol;
+
+// The longest list that `range` creates
+const MAX_LIST_LENGTH: usize = 1_000_000;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Function {
     Sqrt,
     Pow,
-    If
+    If,
+    List,
+    Map,
+    Filter,
+    Reduce,
+    Range,
+    Len,
+    Nth,
+    Sort,
+    Sum,
+    Det,
+    Inv,
+    Transpose,
+    Trace,
+    Rank,
+    Solve,
+    Format
 }
 
 impl Function {
     pub fn eval(&self, args: &[Expression], env: &Environment) -> CalcResult {
         match *self {
-            Function::Sqrt => {
-                if args.len() != 1 {
-                    Err("'sqrt' requires one argument".into())
+            Function::If => {
+                self.arity().check(self.name(), args.len())?;
+
+                // Only the selected branch is evaluated
+                if is_true(args[0].eval(env)?, env)? {
+                    args[1].eval(env)
                 } else {
-                    let x = try!(args[0].eval(env));
-                    Ok(x.sqrt())
+                    args[2].eval(env)
                 }
             }
+            _ => {
+                let values = args.iter().map(|a| a.eval(env)).collect::<CalcResult<Vec<_>>>()?;
+                self.apply(values, env)
+            }
+        }
+    }
+
+    // Applies the function to arguments that have already been evaluated, like `Operator::apply`
+    pub fn apply<I>(&self, args: I, env: &Environment) -> CalcResult
+    where I: IntoIterator<Item=Value>, I::IntoIter: ExactSizeIterator
+    {
+        let mut args = args.into_iter();
+        self.arity().check(self.name(), args.len())?;
+
+        // When several arguments have the wrong type, the last one is reported
+        let mode = env.numeric_mode();
+        match *self {
+            Function::Sqrt => {
+                let x = Quantity::from_value(args.next().unwrap())?;
+                Ok(x.sqrt(mode)?.into_value(mode))
+            }
             Function::Pow => {
-                if args.len() != 2 {
-                    Err("'pow' requires two arguments".into())
-                } else {
-                    let base = try!(args[0].eval(env));
-                    let exponent = try!(args[1].eval(env));
-                    Ok(base.powf(exponent))
-                }
+                let base = args.next().unwrap();
+                let exponent = args.next().unwrap().into_number()?;
+                let base = Quantity::from_value(base)?;
+                Ok(base.pow(&exponent, mode)?.into_value(mode))
             }
             Function::If => {
-                if args.len() != 3 {
-                    Err("'if' requires three arguments".into())
-                } else {
-                    let condition = try!(args[0].eval(env));
+                let condition = args.next().unwrap();
+                let then_value = args.next().unwrap();
+                let else_value = args.next().unwrap();
+                Ok(if is_true(condition, env)? { then_value } else { else_value })
+            }
+            Function::List => {
+                Ok(Value::List(args.collect()))
+            }
+            Function::Map => {
+                let f = args.next().unwrap();
+                let list = args.next().unwrap().into_list()?;
+                let f = f.into_callable()?;
+                list.into_iter().map(|x| f.call(vec![x], env)).collect::<CalcResult<_>>().map(Value::List)
+            }
+            Function::Filter => {
+                let f = args.next().unwrap();
+                let list = args.next().unwrap().into_list()?;
+                let f = f.into_callable()?;
+                let mut result = vec![];
+                for x in list {
+                    if is_true(f.call(vec![x.clone()], env)?, env)? {
+                        result.push(x);
+                    }
+                }
+                Ok(Value::List(result))
+            }
+            Function::Reduce => {
+                // The initial value is optional: without it, the first element is used
+                let f = args.next().unwrap();
+                let initial = if args.len() == 2 { args.next() } else { None };
+                let mut list = args.next().unwrap().into_list()?.into_iter();
+                let initial = initial.or_else(|| list.next());
+                let f = f.into_callable()?;
+                match initial {
+                    Some(initial) => list.try_fold(initial, |acc, x| f.call(vec![acc, x], env)),
+                    None          => Err(CalcError::domain("'reduce' of an empty list requires an initial value"))
+                }
+            }
+            Function::Range => {
+                // (range end), (range start end) or (range start end step)
+                let (start, end, step) = match args.len() {
+                    1 => (Number::from(0), args.next().unwrap().into_number()?, Number::from(1)),
+                    2 => {
+                        let start = args.next().unwrap();
+                        let end = args.next().unwrap().into_number()?;
+                        (start.into_number()?, end, Number::from(1))
+                    }
+                    _ => {
+                        let start = args.next().unwrap();
+                        let end = args.next().unwrap();
+                        let step = args.next().unwrap().into_number()?;
+                        let end = end.into_number()?;
+                        (start.into_number()?, end, step)
+                    }
+                };
 
-                    // 0 means false, other means true
-                    if condition == 0. {
-                        Ok(try!(args[2].eval(env)))
-                    } else {
-                        Ok(try!(args[1].eval(env)))
+                let direction = match step.partial_cmp(&Number::from(0)) {
+                    Some(Ordering::Equal) | None => {
+                        return Err(CalcError::domain("The step of 'range' must be a non-zero real number"));
                     }
+                    Some(direction) => direction
+                };
+
+                let mut result = vec![];
+                let mut x = start;
+                while end.partial_cmp(&x) == Some(direction) {
+                    if result.len() == MAX_LIST_LENGTH {
+                        return Err(CalcError::limit_exceeded(
+                            format!("'range' would create a list with more than {} elements", MAX_LIST_LENGTH)));
+                    }
+                    let next = &x + &step;
+                    result.push(Value::Number(mode.apply(x)));
+                    x = next;
+                }
+                Ok(Value::List(result))
+            }
+            Function::Len => {
+                let list = args.next().unwrap().into_list()?;
+                Ok(Value::Number(Number::from(list.len() as i64)))
+            }
+            Function::Nth => {
+                // Lists are indexed from 0
+                let list = args.next().unwrap();
+                let index = args.next().unwrap().into_number()?;
+                let mut list = list.into_list()?;
+                match index.to_i32().filter(|&i| i >= 0 && (i as usize) < list.len()) {
+                    Some(i) => Ok(list.swap_remove(i as usize)),
+                    None    => Err(CalcError::domain(format!("Index {} is out of bounds for a list of length {}", index, list.len())))
+                }
+            }
+            Function::Sort => {
+                let list = args.next().unwrap().into_list()?;
+                let mut keyed = list.into_iter()
+                                    .map(|x| Quantity::from_value(x.clone()).map(|q| (q, x)))
+                                    .collect::<CalcResult<Vec<_>>>()?;
+
+                // Remember the first comparison that fails, like one between different dimensions
+                let mut error = None;
+                keyed.sort_by(|a, b| a.0.compare(&b.0).unwrap_or_else(|e| {
+                    error.get_or_insert(e);
+                    Ordering::Equal
+                }));
+
+                match error {
+                    Some(e) => Err(e),
+                    None    => Ok(Value::List(keyed.into_iter().map(|(_, x)| x).collect()))
                 }
             }
+            Function::Sum => {
+                let list = args.next().unwrap().into_list()?;
+                let mut values = list.into_iter();
+                let first = match values.next() {
+                    Some(x) => Quantity::from_value(x)?,
+                    None    => return Ok(Value::Number(Number::from(0)))
+                };
+                values.try_fold(first, |acc, x| acc.add(&Quantity::from_value(x)?))
+                      .map(|q| q.into_value(mode))
+            }
+            Function::Det => {
+                let m = args.next().unwrap().into_matrix()?;
+                Ok(Value::Number(mode.apply(m.det()?)))
+            }
+            Function::Inv => {
+                let m = args.next().unwrap().into_matrix()?;
+                Ok(Value::Matrix(m.inv()?.apply_mode(mode)))
+            }
+            Function::Transpose => {
+                let m = args.next().unwrap().into_matrix()?;
+                Ok(Value::Matrix(m.transpose()))
+            }
+            Function::Trace => {
+                let m = args.next().unwrap().into_matrix()?;
+                Ok(Value::Number(mode.apply(m.trace()?)))
+            }
+            Function::Rank => {
+                let m = args.next().unwrap().into_matrix()?;
+                Ok(Value::Number(Number::from(m.rank() as i64)))
+            }
+            Function::Solve => {
+                // The right-hand side can be a matrix or a list, and the solution has the same kind
+                let a = args.next().unwrap().into_matrix()?;
+                match args.next().unwrap() {
+                    Value::List(xs) => {
+                        let xs = xs.into_iter().map(Value::into_number).collect::<CalcResult<Vec<_>>>()?;
+                        let x = a.solve(&Matrix::new(xs.len(), 1, xs))?;
+                        Ok(Value::List(x.apply_mode(mode).into_elements().into_iter().map(Value::Number).collect()))
+                    }
+                    other => Ok(Value::Matrix(a.solve(&other.into_matrix()?)?.apply_mode(mode)))
+                }
+            }
+            Function::Format => {
+                let template = args.next().unwrap().into_str()?;
+                format::format(&template, args.collect()).map(Value::Str)
+            }
+        }
+    }
+
+    // The number of arguments the function accepts
+    pub fn arity(&self) -> Arity {
+        match *self {
+            Function::List => Arity::AtLeast(0),
+            Function::Format => Arity::AtLeast(1),
+            Function::Reduce => Arity::Between(2, 3),
+            Function::Range => Arity::Between(1, 3),
+            Function::Pow | Function::Map | Function::Filter | Function::Nth | Function::Solve => Arity::Exactly(2),
+            Function::If => Arity::Exactly(3),
+            Function::Sqrt | Function::Len | Function::Sort | Function::Sum | Function::Det
+            | Function::Inv | Function::Transpose | Function::Trace | Function::Rank => Arity::Exactly(1)
         }
     }
 
+    // Pure functions always give the same result for the same arguments. The result of `if`
+    // depends on the compatibility mode, and higher-order functions call other functions
+    pub fn is_pure(&self) -> bool {
+        !matches!(*self, Function::If | Function::Map | Function::Filter | Function::Reduce)
+    }
+
     pub fn from_str(s: &str) -> CalcResult<Function> {
-        match s {
-            "sqrt" => Ok(Function::Sqrt),
-            "pow"  => Ok(Function::Pow),
-            "if"   => Ok(Function::If),
-            _      => Err(format!("Unknown function '{}'", s).into())
+        match NAMES.iter().find(|&&(name, _)| name == s) {
+            Some(&(_, f)) => Ok(f),
+            None          => Err(CalcError::unknown_name(s))
+        }
+    }
+
+    pub fn names() -> impl Iterator<Item=&'static str> {
+        NAMES.iter().map(|&(name, _)| name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        NAMES.iter().find(|&&(_, f)| f == *self).map(|&(name, _)| name).unwrap()
+    }
+}
+
+static NAMES: [(&str, Function); 19] = [
+    ("sqrt",   Function::Sqrt),
+    ("pow",    Function::Pow),
+    ("if",     Function::If),
+    ("list",   Function::List),
+    ("map",    Function::Map),
+    ("filter", Function::Filter),
+    ("reduce", Function::Reduce),
+    ("range",  Function::Range),
+    ("len",    Function::Len),
+    ("nth",    Function::Nth),
+    ("sort",   Function::Sort),
+    ("sum",    Function::Sum),
+    ("det",    Function::Det),
+    ("inv",    Function::Inv),
+    ("transpose", Function::Transpose),
+    ("trace",  Function::Trace),
+    ("rank",   Function::Rank),
+    ("solve",  Function::Solve),
+    ("format", Function::Format)
+];
+
+// Interprets a value as a condition. In compatibility mode 0 means false and
+// other numbers mean true
+pub fn is_true(value: Value, env: &Environment) -> CalcResult<bool> {
+    match value {
+        Value::Number(ref x) if env.compatibility_mode() => Ok(!x.is_zero()),
+        other => other.as_bool()
+    }
+}
+
+// A function defined by the user through `(def (name params...) body)`,
+// or an anonymous one created with `(lambda (params...) body)`
+#[derive(Debug)]
+pub struct UserFunction {
+    pub name: Symbol,
+    pub params: Vec<Symbol>,
+    pub body: Expression,
+    code: OnceCell<Chunk>       // The body compiled to bytecode, the first time it is needed
+}
+
+impl UserFunction {
+    pub fn new(name: Symbol, params: Vec<Symbol>, mut body: Expression) -> CalcResult<UserFunction> {
+        // Parameter names must be unique, otherwise one of them would be unreachable
+        let mut seen = HashSet::new();
+        for param in &params {
+            if !seen.insert(param) {
+                return Err(CalcError::syntax(format!("Duplicate parameter '{}' in function '{}'", param, name)));
+            }
+        }
+
+        resolve::resolve(&mut body, &params);
+        Ok(UserFunction { name, params, body, code: OnceCell::new() })
+    }
+
+    // Calls a function defined at the top level, which only sees the global scope
+    pub fn eval(&self, args: &[Expression], env: &Environment) -> CalcResult {
+        // The arguments are evaluated in the environment of the caller
+        let values = args.iter().map(|a| a.eval(env)).collect::<CalcResult<Vec<_>>>()?;
+        self.call(&Scope::default(), &values, env)
+    }
+
+    // Binds the arguments to the parameters in a new scope inside `scope`, the one the
+    // function was created in, and evaluates the body
+    pub fn call(&self, scope: &Scope, args: &[Value], env: &Environment) -> CalcResult {
+        // The name is only looked up for the error, since that locks the interner
+        if args.len() != self.params.len() {
+            return Err(CalcError::arity(self.name.as_str(), Arity::Exactly(self.params.len()), args.len()));
+        }
+
+        let bind = || self.params.iter().cloned().zip(args.iter().cloned()).collect();
+        let result = match env.evaluator() {
+            Evaluator::Tree => self.body.eval(&env.enter_call(scope, Some(bind()))?),
+            Evaluator::Bytecode => {
+                // The bytecode reads the arguments from their slots, so it usually doesn't need them
+                // in the scope
+                let code = self.code(env.numeric_mode());
+                let params = if code.needs_scope { Some(bind()) } else { None };
+                vm::execute(code, &env.enter_call(scope, params)?, args)
+            }
+        };
+
+        // The body may come from an earlier input, so errors point at the call instead
+        result.map_err(|e| e.without_span())
+    }
+
+    // The body is compiled the first time it is run, in the numeric mode of that call
+    fn code(&self, mode: NumericMode) -> &Chunk {
+        self.code.get_or_init(|| compiler::compile(&self.body, &self.params, mode))
+    }
+}
+
+// Anything that can be called with a list of values. User functions keep the scope they were
+// created in, which is empty for the ones defined at the top level
+#[derive(Clone, Debug)]
+pub enum Callable {
+    Builtin(Function),
+    User(Rc<UserFunction>, Scope)
+}
+
+impl Callable {
+    pub fn call(&self, args: Vec<Value>, env: &Environment) -> CalcResult {
+        match self {
+            Callable::Builtin(f)     => f.apply(args, env),
+            Callable::User(f, scope) => f.call(scope, &args, env)
+        }
+    }
+}
+
+// Builtins are equal if they are the same function, user functions if they are the same object
+// created in the same scope
+impl PartialEq for Callable {
+    fn eq(&self, other: &Callable) -> bool {
+        match (self, other) {
+            (Callable::Builtin(f), Callable::Builtin(g)) => f == g,
+            (Callable::User(f, a), Callable::User(g, b)) => Rc::ptr_eq(f, g) && a.ptr_eq(b),
+            _                                            => false
+        }
+    }
+}
+
+impl fmt::Display for Callable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Callable::Builtin(func) => write!(f, "<builtin {}>", func.name()),
+            Callable::User(func, _) => write!(f, "<function {}>", func.name)
         }
     }
 }
@@ -0,0 +1,255 @@
+/*
+
+Implements matrices of numbers and the linear algebra builtins. Gaussian
+elimination is done with the numeric tower, so matrices of integers and
+rationals give exact results.
+
+*/
+
+use std::fmt;
+
+use super::CalcResult;
+use super::error::CalcError;
+use super::number::{Number, NumericMode};
+
+// Float pivots smaller than this, relative to the largest element, are treated as zero
+const FLOAT_TOLERANCE: f64 = 1e-12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<Number>
+}
+
+impl Matrix {
+    // Builds a matrix from its elements in row-major order
+    pub fn new(rows: usize, cols: usize, data: Vec<Number>) -> Matrix {
+        assert_eq!(rows * cols, data.len());
+        Matrix { rows, cols, data }
+    }
+
+    fn identity(n: usize) -> Matrix {
+        let data = (0..n * n).map(|i| Number::from(if i / n == i % n { 1 } else { 0 })).collect();
+        Matrix::new(n, n, data)
+    }
+
+    pub fn shape(&self) -> String {
+        format!("{}x{}", self.rows, self.cols)
+    }
+
+    pub fn into_elements(self) -> Vec<Number> {
+        self.data
+    }
+
+    fn get(&self, row: usize, col: usize) -> &Number {
+        &self.data[row * self.cols + col]
+    }
+
+    pub fn apply_mode(self, mode: NumericMode) -> Matrix {
+        let data = self.data.into_iter().map(|x| mode.apply(x)).collect();
+        Matrix { data, ..self }
+    }
+
+    pub fn add(&self, other: &Matrix) -> CalcResult<Matrix> {
+        self.check_same_shape(other, "add")?;
+        let data = self.data.iter().zip(&other.data).map(|(x, y)| x + y).collect();
+        Ok(Matrix::new(self.rows, self.cols, data))
+    }
+
+    pub fn sub(&self, other: &Matrix) -> CalcResult<Matrix> {
+        self.check_same_shape(other, "subtract")?;
+        let data = self.data.iter().zip(&other.data).map(|(x, y)| x - y).collect();
+        Ok(Matrix::new(self.rows, self.cols, data))
+    }
+
+    // The matrix product
+    pub fn mul(&self, other: &Matrix) -> CalcResult<Matrix> {
+        if self.cols != other.rows {
+            return Err(CalcError::domain(format!("Cannot multiply matrices of shapes {} and {}", self.shape(), other.shape())));
+        }
+
+        let mut data = Vec::with_capacity(self.rows * other.cols);
+        for i in 0..self.rows {
+            for j in 0..other.cols {
+                let sum = (0..self.cols).fold(Number::from(0), |acc, k| &acc + &(self.get(i, k) * other.get(k, j)));
+                data.push(sum);
+            }
+        }
+        Ok(Matrix::new(self.rows, other.cols, data))
+    }
+
+    pub fn scale(&self, factor: &Number) -> Matrix {
+        Matrix::new(self.rows, self.cols, self.data.iter().map(|x| x * factor).collect())
+    }
+
+    // Divides every element by a number. The caller must make sure that it is not zero
+    pub fn div_scalar(&self, divisor: &Number) -> Matrix {
+        Matrix::new(self.rows, self.cols, self.data.iter().map(|x| x.div(divisor)).collect())
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let data = (0..self.cols).flat_map(|j| (0..self.rows).map(move |i| (i, j)))
+                                 .map(|(i, j)| self.get(i, j).clone())
+                                 .collect();
+        Matrix::new(self.cols, self.rows, data)
+    }
+
+    pub fn trace(&self) -> CalcResult<Number> {
+        self.check_square("trace")?;
+        Ok((0..self.rows).fold(Number::from(0), |acc, i| &acc + self.get(i, i)))
+    }
+
+    pub fn det(&self) -> CalcResult<Number> {
+        self.check_square("det")?;
+        let mut m = self.clone();
+        let elimination = m.eliminate(self.cols);
+
+        // Without a pivot in every column, the matrix is singular
+        if elimination.pivots < self.rows {
+            return Ok(Number::from(0));
+        }
+
+        let product = (0..self.rows).fold(Number::from(1), |acc, i| &acc * m.get(i, i));
+        Ok(if elimination.swaps % 2 == 0 { product } else { -&product })
+    }
+
+    pub fn rank(&self) -> usize {
+        self.clone().eliminate(self.cols).pivots
+    }
+
+    pub fn inv(&self) -> CalcResult<Matrix> {
+        self.check_square("inv")?;
+        self.solve(&Matrix::identity(self.rows))
+            .map_err(|_| CalcError::domain(format!("The {} matrix is singular and has no inverse", self.shape())))
+    }
+
+    // Solves A x = b, where b has one column per right-hand side
+    pub fn solve(&self, b: &Matrix) -> CalcResult<Matrix> {
+        self.check_square("solve")?;
+        if b.rows != self.rows {
+            return Err(CalcError::domain(format!("Cannot solve a system with a {} matrix and a {} right-hand side",
+                               self.shape(), b.shape())));
+        }
+
+        // Reduce the augmented matrix [A | b] to [I | x]
+        let n = self.rows;
+        let cols = n + b.cols;
+        let mut data = Vec::with_capacity(n * cols);
+        for i in 0..n {
+            data.extend(self.data[i * n..(i + 1) * n].iter().cloned());
+            data.extend(b.data[i * b.cols..(i + 1) * b.cols].iter().cloned());
+        }
+        let mut m = Matrix::new(n, cols, data);
+        if m.eliminate(n).pivots < n {
+            return Err(CalcError::domain(format!("The {} matrix is singular, so the system has no unique solution", self.shape())));
+        }
+
+        // Back substitution
+        for i in (0..n).rev() {
+            let pivot = m.get(i, i).clone();
+            for j in 0..cols {
+                let x = m.get(i, j).div(&pivot);
+                m.data[i * cols + j] = x;
+            }
+            for k in 0..i {
+                let factor = m.get(k, i).clone();
+                for j in 0..cols {
+                    let x = m.get(k, j) - &(&factor * m.get(i, j));
+                    m.data[k * cols + j] = x;
+                }
+            }
+        }
+
+        let solution = (0..n).flat_map(|i| (n..cols).map(move |j| (i, j)))
+                             .map(|(i, j)| m.get(i, j).clone())
+                             .collect();
+        Ok(Matrix::new(n, b.cols, solution))
+    }
+
+    // Brings the first `cols` columns to row echelon form with partial pivoting
+    fn eliminate(&mut self, cols: usize) -> Elimination {
+        let scale = self.data.iter().map(|x| x.abs_f64()).fold(0., f64::max);
+        let mut result = Elimination { pivots: 0, swaps: 0 };
+
+        for col in 0..cols {
+            let row = result.pivots;
+            if row == self.rows {
+                break;
+            }
+
+            // Choose the largest pivot, which keeps float errors small
+            let pivot = (row..self.rows).filter(|&r| !is_negligible(self.get(r, col), scale))
+                                        .max_by(|&a, &b| {
+                                            let (a, b) = (self.get(a, col).abs_f64(), self.get(b, col).abs_f64());
+                                            a.partial_cmp(&b).unwrap_or(::std::cmp::Ordering::Equal)
+                                        });
+            let pivot = match pivot {
+                Some(p) => p,
+                None    => continue
+            };
+
+            if pivot != row {
+                for j in 0..self.cols {
+                    self.data.swap(row * self.cols + j, pivot * self.cols + j);
+                }
+                result.swaps += 1;
+            }
+
+            for r in row + 1..self.rows {
+                let factor = self.get(r, col).div(self.get(row, col));
+                for j in col..self.cols {
+                    let x = self.get(r, j) - &(&factor * self.get(row, j));
+                    self.data[r * self.cols + j] = x;
+                }
+            }
+            result.pivots += 1;
+        }
+
+        result
+    }
+
+    fn check_same_shape(&self, other: &Matrix, action: &str) -> CalcResult<()> {
+        if self.rows != other.rows || self.cols != other.cols {
+            return Err(CalcError::domain(format!("Cannot {} matrices of shapes {} and {}", action, self.shape(), other.shape())));
+        }
+        Ok(())
+    }
+
+    fn check_square(&self, function: &str) -> CalcResult<()> {
+        if self.rows != self.cols {
+            return Err(CalcError::domain(format!("'{}' requires a square matrix, found {}", function, self.shape())));
+        }
+        Ok(())
+    }
+}
+
+struct Elimination {
+    pivots: usize,
+    swaps: usize
+}
+
+fn is_negligible(x: &Number, scale: f64) -> bool {
+    match x {
+        Number::Float(_) | Number::Complex(_) => x.abs_f64() <= scale * FLOAT_TOLERANCE,
+        _                                     => x.is_zero()
+    }
+}
+
+// Matrices are printed with the same syntax used to write them
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(matrix")?;
+        for i in 0..self.rows {
+            write!(f, " (")?;
+            for j in 0..self.cols {
+                if j > 0 {
+                    write!(f, " ")?;
+                }
+                fmt::Display::fmt(self.get(i, j), f)?;
+            }
+            write!(f, ")")?;
+        }
+        write!(f, ")")
+    }
+}
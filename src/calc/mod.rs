@@ -7,44 +7,107 @@ implemented.
 
 */
 
-use std::borrow::Cow;
-
 pub use self::environment::Environment;
-use self::parser::AST;
+pub use self::value::Value;
+pub use self::number::NumericMode;
+pub use self::error::CalcError;
+// Part of the arity errors, for code that embeds the calculator
+#[allow(unused_imports)]
+pub use self::error::Arity;
+// Tells apart input that is incomplete from input that is wrong
+#[allow(unused_imports)]
+pub use self::error::LexicalKind;
+pub use self::parser::AST;
+// For code that embeds the calculator, to compile a program once and run it many times
+#[allow(unused_imports)]
+pub use self::program::Program;
+pub use self::vm::Evaluator;
 
 mod scanner;
 mod parser;
+mod analysis;
+mod resolve;
+mod optimize;
+mod compiler;
+mod vm;
+mod program;
 mod environment;
 mod expression;
 mod statement;
 mod constant;
 mod operator;
 mod function;
+mod value;
+mod number;
+mod decimal;
+mod units;
+mod matrix;
+mod format;
 mod buffer;
-mod util;
+mod span;
+mod suggest;
+mod symbol;
+mod error;
 
 // A shortcut for the result type that is used everywhere
-pub type CalcResult<T = f64> = Result<T, Cow<'static, str>>;
+pub type CalcResult<T = Value> = Result<T, CalcError>;
 
 // Evaluates a string
+#[allow(dead_code)]
 pub fn eval(s: &str) -> CalcResult {
-    let tokens = try!(scanner::scan(s.trim()));
-    let ast = try!(parser::parse(&tokens));
+    run(s, &mut Environment::new())
+}
+
+// Runs the code contained in a string, using the given environment. The forms are
+// evaluated in order, and the result of the last one is returned
+pub fn run(s: &str, env: &mut Environment) -> CalcResult {
+    compile(s, env)?.run(env)
+}
 
-    let mut env = Environment::new();
-    match ast {
-        AST::Expression(e) => e.eval(&env),
-        AST::Statement(s)  => s.exec(&mut env).map(|_| 0.)
+// Prepares the code contained in a string to be run. Programs that fail the semantic
+// analysis are rejected, so running them never leaves the environment half updated
+pub fn compile(s: &str, env: &Environment) -> CalcResult<Program> {
+    let tokens = scanner::scan(s)?;
+    let program = parser::parse(&tokens)?;
+    if let Some(e) = analysis::check(&program, env).into_iter().next() {
+        return Err(e);
     }
+    Program::new(program, env)
 }
 
-// Runs the code contained in a string, using the given environment
-pub fn run(s: &str, env: &mut Environment) -> CalcResult {
-    let tokens = try!(scanner::scan(s.trim()));
-    let ast = try!(parser::parse(&tokens));
+// Parses a string without stopping at the first syntax error, so every error can be
+// reported at once. The forms that could be parsed are returned too, for tooling
+pub fn parse_all(s: &str) -> (Vec<AST>, Vec<CalcError>) {
+    match scanner::scan(s) {
+        Ok(tokens) => parser::parse_recovering(&tokens),
+        Err(e)     => (vec![], vec![e])
+    }
+}
+
+// Finds every problem in a string without running it: the syntax errors if there are
+// any, otherwise the arity errors and unknown names found by the semantic analysis
+pub fn check(s: &str, env: &Environment) -> Vec<CalcError> {
+    let (program, errors) = parse_all(s);
+    if errors.is_empty() { analysis::check(&program, env) } else { errors }
+}
+
+// Checks whether the input has parentheses, a block comment or a string that are still open,
+// so more lines are needed to complete it. Other input that can't be scanned is complete, so
+// the error gets reported
+pub fn is_incomplete(s: &str) -> bool {
+    let tokens = match scanner::scan(s) {
+        Ok(tokens) => tokens,
+        Err(e)     => return matches!(e, CalcError::Lexical { kind: LexicalKind::Unterminated, .. })
+    };
 
-    match ast {
-        AST::Expression(e) => e.eval(env),
-        AST::Statement(s)  => s.exec(env).map(|_| 0.)
+    let mut depth = 0;
+    for t in &tokens {
+        match t.token {
+            scanner::Token::LPar => depth += 1,
+            scanner::Token::RPar if depth == 0 => return false,
+            scanner::Token::RPar => depth -= 1,
+            _ => ()
+        }
     }
+    depth > 0
 }
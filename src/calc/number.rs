@@ -0,0 +1,382 @@
+/*
+
+Implements the numeric tower of the calculator. Integers, decimals and
+rationals are exact and have arbitrary precision, while floats are used as
+soon as an inexact operation is involved. Complex numbers are at the top
+of the tower and are always inexact.
+
+In decimal mode, inexact results are decimals rounded to a configurable
+number of significant digits instead of floats.
+
+*/
+
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::num::NonZeroU64;
+use std::ops::{Add, Mul, Neg, Sub};
+
+use bigdecimal::BigDecimal;
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+use num_integer::Integer;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+use super::CalcResult;
+use super::decimal;
+use super::error::CalcError;
+
+// Larger exponents are rejected, to avoid building gigantic integers
+const MAX_EXACT_EXPONENT: usize = 100_000;
+
+// Exact powers whose numerator or denominator would be larger are rejected too, since a big
+// base makes them gigantic with a small exponent. That is about 300000 decimal digits
+const MAX_EXACT_BITS: u64 = 1_000_000;
+
+#[derive(Clone, Debug)]
+pub enum Number {
+    Integer(BigInt),
+    Decimal(BigDecimal),
+    Rational(BigRational),
+    Float(f64),
+    Complex(Complex64)
+}
+
+// Determines how inexact results are represented
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumericMode {
+    Float,
+    Decimal(NonZeroU64)
+}
+
+impl NumericMode {
+    // Brings a number to the representation used by this mode. Integers and rationals are
+    // always kept, so exact arithmetic stays exact in both modes
+    pub fn apply(self, x: Number) -> Number {
+        match (self, x) {
+            (NumericMode::Float, Number::Decimal(d)) => Number::Float(d.to_f64().unwrap_or(f64::NAN)),
+            (NumericMode::Decimal(digits), Number::Decimal(d)) => {
+                Number::Decimal(decimal::round(&d, digits))
+            }
+            (NumericMode::Decimal(digits), Number::Float(x)) => {
+                match decimal::from_f64(x) {
+                    Some(d) => Number::Decimal(decimal::round(&d, digits)),
+                    None    => Number::Float(x)
+                }
+            }
+            (_, x) => x
+        }
+    }
+}
+
+impl Number {
+    pub fn from_str(s: &str) -> Option<Number> {
+        // Words without a fractional part or exponent are exact integers
+        if let Ok(i) = s.parse::<BigInt>() {
+            return Some(Number::Integer(i));
+        }
+
+        // Imaginary literals end with an `i`, like `2i` or `-0.5i`
+        if let Some(im) = s.strip_suffix('i') {
+            return im.parse::<f64>().ok().map(|im| Number::Complex(Complex64::new(0., im)));
+        }
+
+        // Other literals are kept as exact decimals, so that they can be used in decimal mode.
+        // Only words like `inf` need to be parsed as floats
+        if let Ok(d) = s.parse::<BigDecimal>() {
+            return Some(Number::Decimal(d));
+        }
+
+        s.parse::<f64>().ok().map(Number::Float)
+    }
+
+    // Builds a number from a rational, which is turned into an integer if possible
+    pub fn from_rational(r: BigRational) -> Number {
+        if r.is_integer() {
+            Number::Integer(r.to_integer())
+        } else {
+            Number::Rational(r)
+        }
+    }
+
+    // Builds a number from a complex, which is turned into a float if it has no imaginary part
+    pub fn from_complex(c: Complex64) -> Number {
+        if c.im == 0. {
+            Number::Float(c.re)
+        } else {
+            Number::Complex(c)
+        }
+    }
+
+    pub fn is_complex(&self) -> bool {
+        matches!(*self, Number::Complex(_))
+    }
+
+    pub fn to_complex(&self) -> Complex64 {
+        match self {
+            Number::Complex(c) => *c,
+            _                  => Complex64::new(self.to_f64(), 0.)
+        }
+    }
+
+    // Returns the exact value of the number as a rational, if it has one
+    pub fn to_rational(&self) -> Option<BigRational> {
+        match self {
+            Number::Integer(i)  => Some(BigRational::from_integer(i.clone())),
+            Number::Decimal(d)  => Some(decimal::to_rational(d)),
+            Number::Rational(r) => Some(r.clone()),
+            _                   => None
+        }
+    }
+
+    // Returns the value of the number as a decimal, if it can be represented exactly
+    fn to_decimal(&self) -> Option<BigDecimal> {
+        match self {
+            Number::Integer(i) => Some(BigDecimal::from(i.clone())),
+            Number::Decimal(d) => Some(d.clone()),
+            _                  => None
+        }
+    }
+
+    pub fn to_i32(&self) -> Option<i32> {
+        match self {
+            Number::Integer(i) => i.to_i32(),
+            _                  => None
+        }
+    }
+
+    // Non-real numbers have no float representation, so they give NaN
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(i)  => i.to_f64().unwrap_or(f64::NAN),
+            Number::Decimal(d)  => d.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(x)    => *x,
+            Number::Complex(c)  => if c.im == 0. { c.re } else { f64::NAN }
+        }
+    }
+
+    // The absolute value as a float, which is also defined for complex numbers
+    pub fn abs_f64(&self) -> f64 {
+        match self {
+            Number::Complex(c) => c.norm(),
+            _                  => self.to_f64().abs()
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(i)  => i.is_zero(),
+            Number::Decimal(d)  => d.is_zero(),
+            Number::Rational(r) => r.is_zero(),
+            Number::Float(x)    => *x == 0.,
+            Number::Complex(c)  => c.is_zero()
+        }
+    }
+
+    // The square root of a negative number is imaginary
+    pub fn sqrt(&self, mode: NumericMode) -> Number {
+        if let Number::Complex(c) = self {
+            return Number::from_complex(c.sqrt());
+        }
+
+        let x = self.to_f64();
+        if x < 0. {
+            return Number::Complex(Complex64::new(0., (-x).sqrt()));
+        }
+
+        match (mode, self.to_rational()) {
+            (NumericMode::Decimal(digits), Some(r)) => {
+                let d = decimal::from_rational(&r, digits);
+                decimal::sqrt(&d, digits).map(Number::Decimal).unwrap_or(Number::Float(x.sqrt()))
+            }
+            _ => Number::Float(x.sqrt())
+        }
+    }
+
+    pub fn pow(&self, exponent: &Number, mode: NumericMode) -> CalcResult<Number> {
+        // Like dividing by zero, since a negative power is the reciprocal of a positive one
+        if self.is_zero() && !exponent.is_complex() && exponent.to_f64() < 0. {
+            return Err(CalcError::division_by_zero());
+        }
+
+        // Exact numbers raised to an integer exponent stay exact
+        if let (Some(base), Number::Integer(exp)) = (self.to_rational(), exponent) {
+            let inverse = exp.is_negative();
+            let exp = match exp.abs().to_usize().filter(|&e| e <= MAX_EXACT_EXPONENT) {
+                Some(exp) => exp,
+                // Only 0, 1 and -1 have powers that stay small, whatever the exponent
+                None if base.is_zero() || base.abs().is_one() => if exp.is_odd() { 1 } else { 2 },
+                None => {
+                    return Err(CalcError::limit_exceeded(
+                        format!("Exact powers are limited to exponents of at most {}", MAX_EXACT_EXPONENT)));
+                }
+            };
+            // The size is estimated from the bits of the base, before anything is computed
+            let bits = cmp::max(base.numer().bits(), base.denom().bits());
+            if bits.saturating_mul(exp as u64) > MAX_EXACT_BITS {
+                return Err(CalcError::limit_exceeded(
+                    format!("Exact powers are limited to results of at most {} bits", MAX_EXACT_BITS)));
+            }
+            // The powers of a reduced fraction are reduced too, so they don't need normalizing
+            let (numer, denom) = (num_traits::pow(base.numer().clone(), exp), num_traits::pow(base.denom().clone(), exp));
+            let result = BigRational::new_raw(numer, denom);
+            return Ok(Number::from_rational(if inverse { result.recip() } else { result }));
+        }
+
+        // Integer powers of complex numbers are computed by multiplication, which is more accurate
+        if let (Number::Complex(base), Some(exp)) = (self, exponent.to_i32()) {
+            return Ok(Number::from_complex(base.powi(exp)));
+        }
+
+        // Complex numbers and fractional powers of negative numbers give complex results
+        let (base, exp) = (self.to_f64(), exponent.to_f64());
+        if self.is_complex() || exponent.is_complex() || (base < 0. && exp.fract() != 0.) {
+            return Ok(Number::from_complex(self.to_complex().powc(exponent.to_complex())));
+        }
+
+        // In decimal mode, powers of positive numbers are computed with the requested precision
+        if let (NumericMode::Decimal(digits), true) = (mode, base > 0.) {
+            if let (Some(b), Some(e)) = (self.to_rational(), exponent.to_rational()) {
+                let (b, e) = (decimal::from_rational(&b, digits), decimal::from_rational(&e, digits));
+                return Ok(Number::Decimal(decimal::pow(&b, &e, digits)));
+            }
+        }
+
+        Ok(Number::Float(base.powf(exp)))
+    }
+
+    // Divides two numbers. The caller must make sure that the divisor is not zero
+    pub fn div(&self, other: &Number) -> Number {
+        if self.is_complex() || other.is_complex() {
+            return Number::from_complex(self.to_complex() / other.to_complex());
+        }
+
+        match (self.to_rational(), other.to_rational()) {
+            (Some(x), Some(y)) => Number::from_rational(x / y),
+            _                  => Number::Float(self.to_f64() / other.to_f64())
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(i: i64) -> Number {
+        Number::Integer(BigInt::from(i))
+    }
+}
+
+// Applies an exact operation when both numbers are exact, and an inexact one otherwise.
+// Integers and decimals get their own operations, which are faster than going through rationals
+fn binary_op<F, D, G, H, C>(a: &Number, b: &Number, integer: F, decimal: D, rational: G, float: H, complex: C)
+        -> Number
+where F: Fn(&BigInt, &BigInt) -> BigInt,
+      D: Fn(&BigDecimal, &BigDecimal) -> BigDecimal,
+      G: Fn(&BigRational, &BigRational) -> BigRational,
+      H: Fn(f64, f64) -> f64,
+      C: Fn(Complex64, Complex64) -> Complex64
+{
+    if let (Number::Integer(x), Number::Integer(y)) = (a, b) {
+        return Number::Integer(integer(x, y));
+    }
+
+    if a.is_complex() || b.is_complex() {
+        return Number::from_complex(complex(a.to_complex(), b.to_complex()));
+    }
+
+    // Checked before the exact representations, which would be built for nothing
+    if let (Number::Float(_), _) | (_, Number::Float(_)) = (a, b) {
+        return Number::Float(float(a.to_f64(), b.to_f64()));
+    }
+
+    if let (Some(x), Some(y)) = (a.to_decimal(), b.to_decimal()) {
+        return Number::Decimal(decimal(&x, &y));
+    }
+
+    match (a.to_rational(), b.to_rational()) {
+        (Some(x), Some(y)) => Number::from_rational(rational(&x, &y)),
+        _                  => Number::Float(float(a.to_f64(), b.to_f64()))
+    }
+}
+
+macro_rules! impl_arithmetic {
+    ($trait_name:ident, $method:ident, $op:tt) => {
+        impl $trait_name for &Number {
+            type Output = Number;
+            fn $method(self, other: &Number) -> Number {
+                binary_op(self, other, |x, y| x $op y, |x, y| x $op y, |x, y| x $op y,
+                          |x, y| x $op y, |x, y| x $op y)
+            }
+        }
+    }
+}
+
+impl_arithmetic!(Add, add, +);
+impl_arithmetic!(Sub, sub, -);
+impl_arithmetic!(Mul, mul, *);
+
+impl Neg for &Number {
+    type Output = Number;
+    fn neg(self) -> Number {
+        match self {
+            Number::Integer(i)  => Number::Integer(-i),
+            Number::Decimal(d)  => Number::Decimal(-d),
+            Number::Rational(r) => Number::Rational(-r),
+            Number::Float(x)    => Number::Float(-x),
+            Number::Complex(c)  => Number::Complex(-c)
+        }
+    }
+}
+
+// Numbers are compared by value, so `2` and `2.0` are equal
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        if self.is_complex() || other.is_complex() {
+            return self.to_complex() == other.to_complex();
+        }
+
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+// Complex numbers are not ordered
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        if self.is_complex() || other.is_complex() {
+            return None;
+        }
+
+        match (self.to_rational(), other.to_rational()) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _                  => self.to_f64().partial_cmp(&other.to_f64())
+        }
+    }
+}
+
+// Rationals are printed as fractions, like `1/3`. The alternate flag (`{:#}`)
+// prints them as decimals instead, and a precision (`{:.2}`) prints every
+// number with that many decimal places
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(places) = f.precision() {
+            return match self {
+                Number::Float(x)                => write!(f, "{:.*}", places, x),
+                Number::Complex(c) if c.im < 0. => write!(f, "{:.*}-{:.*}i", places, c.re, places, -c.im),
+                Number::Complex(c)              => write!(f, "{:.*}+{:.*}i", places, c.re, places, c.im),
+                _ => {
+                    let r = self.to_rational().unwrap();
+                    write!(f, "{}", decimal::to_fixed(&r, places))
+                }
+            };
+        }
+
+        match self {
+            Number::Integer(i)                   => write!(f, "{}", i),
+            Number::Decimal(d)                   => write!(f, "{}", decimal::to_plain(d)),
+            Number::Rational(r) if f.alternate() => write!(f, "{}", r.to_f64().unwrap_or(f64::NAN)),
+            Number::Rational(r)                  => write!(f, "{}/{}", r.numer(), r.denom()),
+            Number::Float(x)                     => write!(f, "{}", x),
+            Number::Complex(c) if c.im < 0.      => write!(f, "{}-{}i", c.re, -c.im),
+            Number::Complex(c)                   => write!(f, "{}+{}i", c.re, c.im)
+        }
+    }
+}
@@ -5,11 +5,16 @@ Implements the operators of the calculator.
 */
 
 use super::CalcResult;
+use super::error::{Arity, CalcError};
 use super::expression::Expression;
 use super::environment::Environment;
-use super::util::combine;
+use super::function::is_true;
+use super::value::Value;
+use super::number::{Number, NumericMode};
+use super::units::Quantity;
+use super::matrix::Matrix;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Operator {
     Add,
     Sub,
@@ -20,115 +25,278 @@ pub enum Operator {
     LtEq,
     Gt,
     GtEq,
-    NotEq
+    NotEq,
+    And,
+    Or,
+    Not,
+    Xor
 }
 
 impl Operator {
     pub fn eval(&self, args: &[Expression], env: &Environment) -> CalcResult {
+        // `and` and `or` stop evaluating their arguments as soon as the result is known
+        if let Operator::And | Operator::Or = *self {
+            for arg in args {
+                if let Some(result) = self.decides(arg.eval(env)?, env)? {
+                    return Ok(Value::Boolean(result));
+                }
+            }
+            return Ok(Value::Boolean(*self == Operator::And));
+        }
+
+        let values = args.iter().map(|a| a.eval(env)).collect::<CalcResult<Vec<_>>>()?;
+        self.apply(values, env)
+    }
+
+    // Returns the result of `and` or `or` if this argument settles it: a false one for `and`
+    // and a true one for `or`. The elements of a list are arguments too
+    pub fn decides(&self, value: Value, env: &Environment) -> CalcResult<Option<bool>> {
+        let settles = *self == Operator::Or;
+        let values = match value {
+            Value::List(xs) => xs,
+            other           => vec![other]
+        };
+        for x in values {
+            if is_true(x, env)? == settles {
+                return Ok(Some(settles));
+            }
+        }
+        Ok(None)
+    }
+
+    // Applies the operator to arguments that have already been evaluated. They are taken as an
+    // iterator, so the bytecode can move them straight off its stack
+    pub fn apply<I>(&self, args: I, env: &Environment) -> CalcResult
+    where I: IntoIterator<Item=Value>, I::IntoIter: ExactSizeIterator
+    {
         use self::Operator::*;
+        let mut args = args.into_iter();
+        self.arity().check(self.symbol(), args.len())?;
+
         match *self {
             Add => {
-                args.iter().fold(Ok(0.0), |acc, x| {
-                    combine(acc, x.eval(env), |v1, v2| v1 + v2)
-                })
+                // The first argument determines the dimension of the sum
+                let mut values = splice(args)?.into_iter();
+                let first_arg = match values.next() {
+                    Some(x) => x,
+                    None    => return Ok(Value::Number(Number::from(0)))
+                };
+                values.try_fold(first_arg, |acc, x| acc.add(&x))
+                      .map(|x| x.into_value(env.numeric_mode()))
             }
             Sub => {
-                if args.len() == 0 {
-                    return Err("Substraction requires at least one argument".into());
-                }
-                let first_arg = args[0].eval(env);
-                args[1..].iter().fold(first_arg, |acc, x| {
-                    combine(acc, x.eval(env), |v1, v2| v1 - v2)
-                })
+                // Splicing an empty list can still leave nothing to subtract from
+                let mut values = splice(args)?.into_iter();
+                let first_arg = match values.next() {
+                    Some(x) => x,
+                    None    => return Err(CalcError::arity(self.symbol(), Arity::AtLeast(1), 0))
+                };
+                values.try_fold(first_arg, |acc, x| acc.sub(&x))
+                      .map(|x| x.into_value(env.numeric_mode()))
             }
             Mul => {
-                args.iter().fold(Ok(1.0), |acc, x| {
-                    combine(acc, x.eval(env), |v1, v2| v1 * v2)
-                })
+                // Matrices don't commute, so the product is taken from left to right
+                let values = splice(args)?;
+                let one = Operand::Scalar(Quantity::from(Number::from(1)));
+                values.iter().try_fold(one, |acc, x| acc.mul(x))
+                      .map(|x| x.into_value(env.numeric_mode()))
             }
             Div => {
-                if args.len() != 2 {
-                    return Err("Division requires two arguments".into());
-                }
-                let first_arg = args[0].eval(env);
-                if args[1..].iter().any(|x| x.eval(env) == Ok(0.0)) {
-                    return Err("Cannot divide by 0".into());
+                let dividend = Operand::from_value(args.next().unwrap())?;
+                let divisor = Quantity::from_value(args.next().unwrap())?;
+                if divisor.magnitude.is_zero() {
+                    return Err(CalcError::division_by_zero());
                 }
-                args[1..].iter().fold(first_arg, |acc, x| {
-                    combine(acc, x.eval(env), |v1, v2| v1 / v2)
-                })
+                dividend.div(&divisor).map(|x| x.into_value(env.numeric_mode()))
             }
             Eq => {
-                if args.len() < 2 {
-                    return Err("== requires at least two arguments".into());
-                }
-
-                let mut equal = false;
-                let first = try!(args[0].eval(env));
-                for x in &args[1..] {
-                    equal = equal && try!(x.eval(env)) == first;
-                }
-
-                if equal { Ok(1.) } else { Ok(0.) }
+                let first = args.next().unwrap();
+                Ok(Value::Boolean(args.all(|x| x == first)))
             }
             Lt => {
-                if args.len() != 2 {
-                    return Err("< requires two arguments".into());
-                }
-                let (arg1, arg2) = (try!(args[0].eval(env)), try!(args[1].eval(env)));
-                Ok(bool_to_f64(arg1 < arg2))
+                let (arg1, arg2) = ordered(args)?;
+                Ok(Value::Boolean(arg1 < arg2))
             }
             LtEq => {
-                if args.len() != 2 {
-                    return Err("<= requires two arguments".into());
-                }
-                Operator::Gt.eval(args, env).map(negate_f64)
+                let (arg1, arg2) = ordered(args)?;
+                Ok(Value::Boolean(arg1 <= arg2))
             }
             Gt => {
-                if args.len() != 2 {
-                    return Err("> requires two arguments".into());
-                }
-                let (arg1, arg2) = (try!(args[0].eval(env)), try!(args[1].eval(env)));
-                Ok(bool_to_f64(arg1 > arg2))
+                let (arg1, arg2) = ordered(args)?;
+                Ok(Value::Boolean(arg1 > arg2))
             }
             GtEq => {
-                if args.len() != 2 {
-                    return Err(">= requires two arguments".into());
-                }
-                Operator::Lt.eval(args, env).map(negate_f64)
+                let (arg1, arg2) = ordered(args)?;
+                Ok(Value::Boolean(arg1 >= arg2))
             }
             NotEq => {
-                if args.len() != 2 {
-                    return Err("!= requires two arguments".into());
+                Ok(Value::Boolean(args.next() != args.next()))
+            }
+            And | Or => {
+                for arg in args {
+                    if let Some(result) = self.decides(arg, env)? {
+                        return Ok(Value::Boolean(result));
+                    }
                 }
-                Operator::Eq.eval(args, env).map(negate_f64)
+                Ok(Value::Boolean(*self == And))
+            }
+            Not => {
+                Ok(Value::Boolean(!is_true(args.next().unwrap(), env)?))
             }
+            Xor => {
+                let x = is_true(args.next().unwrap(), env)?;
+                Ok(Value::Boolean(x != is_true(args.next().unwrap(), env)?))
+            }
+        }
+    }
+
+    // The number of arguments the operator accepts, before lists are spliced
+    pub fn arity(&self) -> Arity {
+        use self::Operator::*;
+        match *self {
+            Add | Mul | And | Or => Arity::AtLeast(0),
+            Sub                  => Arity::AtLeast(1),
+            Eq                   => Arity::AtLeast(2),
+            Not                  => Arity::Exactly(1),
+            Div | Lt | LtEq | Gt | GtEq | NotEq | Xor => Arity::Exactly(2)
         }
     }
 
+    // Like `Function::is_pure`. The logical operators depend on the compatibility mode
+    pub fn is_pure(&self) -> bool {
+        !matches!(*self, Operator::And | Operator::Or | Operator::Not | Operator::Xor)
+    }
+
     pub fn from_str(s: &str) -> Option<Operator> {
-        match s {
-            "+"  => Some(Operator::Add),
-            "-"  => Some(Operator::Sub),
-            "*"  => Some(Operator::Mul),
-            "/"  => Some(Operator::Div),
-            "==" => Some(Operator::Eq),
-            "<"  => Some(Operator::Lt),
-            "<=" => Some(Operator::LtEq),
-            ">"  => Some(Operator::Gt),
-            ">=" => Some(Operator::GtEq),
-            "!=" => Some(Operator::NotEq),
-            _    => None
+        SYMBOLS.iter().find(|&&(symbol, _)| symbol == s).map(|&(_, op)| op)
+    }
+
+    pub fn symbols() -> impl Iterator<Item=&'static str> {
+        SYMBOLS.iter().map(|&(symbol, _)| symbol)
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        SYMBOLS.iter().find(|&&(_, op)| op == *self).map(|&(symbol, _)| symbol).unwrap()
+    }
+}
+
+static SYMBOLS: [(&str, Operator); 14] = [
+    ("+",   Operator::Add),
+    ("-",   Operator::Sub),
+    ("*",   Operator::Mul),
+    ("/",   Operator::Div),
+    ("==",  Operator::Eq),
+    ("<",   Operator::Lt),
+    ("<=",  Operator::LtEq),
+    (">",   Operator::Gt),
+    (">=",  Operator::GtEq),
+    ("!=",  Operator::NotEq),
+    ("and", Operator::And),
+    ("or",  Operator::Or),
+    ("not", Operator::Not),
+    ("xor", Operator::Xor)
+];
+
+// The elements of list arguments are used as arguments too, so `(+ (list 1 2) 3)`
+// is the same as `(+ 1 2 3)`
+fn splice<I: Iterator<Item=Value>>(args: I) -> CalcResult<Vec<Operand>> {
+    let mut values = vec![];
+    for arg in args {
+        match arg {
+            Value::List(xs) => {
+                for x in xs {
+                    values.push(Operand::from_value(x)?);
+                }
+            }
+            other => values.push(Operand::from_value(other)?)
         }
     }
+    Ok(values)
 }
 
-// We use 0 as false and any other value as true
-fn bool_to_f64(b: bool) -> f64 {
-    if b { 1. } else { 0. }
+// The arguments of arithmetic operators
+enum Operand {
+    Scalar(Quantity),
+    Matrix(Matrix),
+    Str(String)
+}
+
+impl Operand {
+    fn from_value(value: Value) -> CalcResult<Operand> {
+        match value {
+            Value::Matrix(m) => Ok(Operand::Matrix(m)),
+            Value::Str(s)    => Ok(Operand::Str(s)),
+            other            => Quantity::from_value(other).map(Operand::Scalar)
+        }
+    }
+
+    fn into_value(self, mode: NumericMode) -> Value {
+        match self {
+            Operand::Scalar(q) => q.into_value(mode),
+            Operand::Matrix(m) => Value::Matrix(m.apply_mode(mode)),
+            Operand::Str(s)    => Value::Str(s)
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Operand::Scalar(_) => "number",
+            Operand::Matrix(_) => "matrix",
+            Operand::Str(_)    => "string"
+        }
+    }
+
+    // Matrix addition is element-wise, so both operands must be matrices. Adding strings concatenates them
+    fn add(&self, other: &Operand) -> CalcResult<Operand> {
+        match (self, other) {
+            (Operand::Scalar(x), Operand::Scalar(y)) => x.add(y).map(Operand::Scalar),
+            (Operand::Matrix(x), Operand::Matrix(y)) => x.add(y).map(Operand::Matrix),
+            (Operand::Str(x), Operand::Str(y))       => Ok(Operand::Str(format!("{}{}", x, y))),
+            _ => Err(CalcError::domain(format!("Cannot add a {} and a {}", self.kind(), other.kind())))
+        }
+    }
+
+    fn sub(&self, other: &Operand) -> CalcResult<Operand> {
+        match (self, other) {
+            (Operand::Scalar(x), Operand::Scalar(y)) => x.sub(y).map(Operand::Scalar),
+            (Operand::Matrix(x), Operand::Matrix(y)) => x.sub(y).map(Operand::Matrix),
+            _ => Err(CalcError::domain(format!("Cannot subtract a {} and a {}", self.kind(), other.kind())))
+        }
+    }
+
+    // Matrices are multiplied with the matrix product, or scaled by a number
+    fn mul(&self, other: &Operand) -> CalcResult<Operand> {
+        match (self, other) {
+            (Operand::Scalar(x), Operand::Scalar(y)) => x.mul(y).map(Operand::Scalar),
+            (Operand::Matrix(x), Operand::Matrix(y)) => x.mul(y).map(Operand::Matrix),
+            (Operand::Scalar(q), Operand::Matrix(m)) |
+            (Operand::Matrix(m), Operand::Scalar(q)) => Ok(Operand::Matrix(m.scale(scalar_factor(q)?))),
+            _ => Err(CalcError::domain(format!("Cannot multiply a {} and a {}", self.kind(), other.kind())))
+        }
+    }
+
+    // Divides by a non-zero quantity
+    fn div(&self, divisor: &Quantity) -> CalcResult<Operand> {
+        match self {
+            Operand::Scalar(x) => x.div(divisor).map(Operand::Scalar),
+            Operand::Matrix(m) => Ok(Operand::Matrix(m.div_scalar(scalar_factor(divisor)?))),
+            Operand::Str(_)    => Err(CalcError::domain("Cannot divide a string"))
+        }
+    }
+}
+
+// Matrices can only hold plain numbers, so they can't be scaled by a quantity with units
+fn scalar_factor(q: &Quantity) -> CalcResult<&Number> {
+    if !q.dimension.is_dimensionless() {
+        return Err(CalcError::domain(format!("Cannot scale a matrix by the quantity '{}'", q)));
+    }
+    Ok(&q.magnitude)
 }
 
-// Negates a f64 which is used as a boolean
-fn negate_f64(n: f64) -> f64 {
-    if n == 0. { 1. } else { 0. }
+// The two arguments of a comparison, which must be real and have the same dimension
+fn ordered<I: Iterator<Item=Value>>(mut args: I) -> CalcResult<(Number, Number)> {
+    let x = Quantity::from_value(args.next().unwrap())?;
+    let y = Quantity::from_value(args.next().unwrap())?;
+    x.check_comparable(&y)?;
+    Ok((x.magnitude, y.magnitude))
 }
@@ -0,0 +1,128 @@
+/*
+
+Implements an optimization pass, which computes the constant parts of expressions ahead
+of time so they are not recomputed every time a function body is evaluated.
+
+An expression is constant when it is made only of literals, constants, units, pure operators
+and pure builtins. Constants and builtins can be shadowed by user-defined variables and
+functions, even ones defined after the expression was folded, so a folded value remembers
+the names it depends on and the numeric mode it was computed in. When any of those change,
+the original expression is evaluated instead.
+
+*/
+
+use std::mem;
+use std::rc::Rc;
+
+use num_traits::One;
+
+use super::environment::Environment;
+use super::expression::{Binding, Builtin, ExprType, Expression};
+use super::number::Number;
+use super::operator::Operator;
+use super::parser::AST;
+use super::statement::StmtType;
+use super::symbol::Symbol;
+
+pub fn optimize(ast: &mut AST, env: &Environment) {
+    match *ast {
+        AST::Expression(ref mut e) => optimize_expr(e, env, &[]),
+        AST::Statement(ref mut s) => {
+            let params = match s.stmt_type {
+                StmtType::Assign | StmtType::Define => &[][..],
+                StmtType::FuncDef                   => &s.params[..]
+            };
+            optimize_expr(&mut s.rhs, env, params);
+        }
+    }
+}
+
+// Optimizes an expression, where `locals` are the parameters of the enclosing functions
+fn optimize_expr(e: &mut Expression, env: &Environment, locals: &[Symbol]) {
+    if let Some(names) = fold(e, env, locals) {
+        replace_by_value(e, env, names);
+    }
+}
+
+// Returns the names a constant expression depends on, or None if it isn't constant.
+// In that case, the largest constant subexpressions are replaced by their values
+fn fold(e: &mut Expression, env: &Environment, locals: &[Symbol]) -> Option<Vec<Symbol>> {
+    if let ExprType::Lambda(ref mut f) = e.expr_type {
+        // Lambdas that were just parsed are not shared yet
+        if let Some(f) = Rc::get_mut(f) {
+            let mut params = locals.to_vec();
+            params.extend(&f.params);
+            optimize_expr(&mut f.body, env, &params);
+        }
+        return None;
+    }
+
+    if let ExprType::Let(ref names) = e.expr_type {
+        // The names of the block hide any builtin with the same name inside it
+        let mut inner = locals.to_vec();
+        inner.extend(names);
+        for arg in &mut e.args {
+            optimize_expr(arg, env, &inner);
+        }
+        return None;
+    }
+
+    let args: Vec<_> = e.args.iter_mut().map(|a| fold(a, env, locals)).collect();
+    if let Some(mut names) = own_names(e, env, locals).filter(|_| args.iter().all(Option::is_some)) {
+        names.extend(args.into_iter().flat_map(Option::unwrap));
+        names.sort();
+        names.dedup();
+        return Some(names);
+    }
+
+    for (arg, names) in e.args.iter_mut().zip(args) {
+        if let Some(names) = names {
+            replace_by_value(arg, env, names);
+        }
+    }
+    remove_identities(e);
+    None
+}
+
+// The names an expression depends on by itself, if it is constant when its arguments are
+fn own_names(e: &Expression, env: &Environment, locals: &[Symbol]) -> Option<Vec<Symbol>> {
+    let builtin = |name: Symbol| !locals.contains(&name) && !env.is_defined(name);
+    match e.expr_type {
+        ExprType::Number(_) | ExprType::Str(_) | ExprType::Matrix(_) => Some(vec![]),
+        ExprType::Operator(op) if op.is_pure() => Some(vec![]),
+        ExprType::Variable(name, Binding::Global(Builtin::Constant(_)))
+        | ExprType::Variable(name, Binding::Global(Builtin::Unit(_))) if builtin(name) => Some(vec![name]),
+        ExprType::Function(name, Some(f)) if f.is_pure() && builtin(name) => Some(vec![name]),
+        _ => None
+    }
+}
+
+// Evaluates a constant expression and keeps the original as its only argument, in case the
+// value can't be used. Literals are cheap already, and errors are left to be reported when
+// the expression is actually evaluated
+fn replace_by_value(e: &mut Expression, env: &Environment, names: Vec<Symbol>) {
+    if let ExprType::Number(_) | ExprType::Str(_) = e.expr_type {
+        return;
+    }
+
+    if let Ok(value) = e.eval(env) {
+        let folded = Expression::from_type(ExprType::Folded(value, env.numeric_mode(), names), e.span);
+        let original = mem::replace(e, folded);
+        e.args.push(original);
+    }
+}
+
+// Removes factors of exactly 1 from products. Products start from 1, so this doesn't change
+// their value even for floats. Adding 0 is not removed, because `(+ x 0)` turns -0 into 0 and
+// fails when `x` is a string or has a dimension
+fn remove_identities(e: &mut Expression) {
+    if let ExprType::Operator(Operator::Mul) = e.expr_type {
+        let is_one = |a: &Expression| matches!(a.expr_type, ExprType::Number(Number::Integer(ref i)) if i.is_one());
+        while e.args.len() > 1 {
+            match e.args.iter().position(is_one) {
+                Some(i) => { e.args.remove(i); }
+                None    => break
+            }
+        }
+    }
+}
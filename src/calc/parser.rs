@@ -6,131 +6,516 @@ expression is evaluated.
 
 */
 
+use std::rc::Rc;
+use std::slice;
+
 use super::CalcResult;
-use super::scanner::{Keyword, Token};
+use super::error::CalcError;
+use super::scanner::{Keyword, SpannedToken, Token};
+use super::span::Span;
 use super::expression::{self, Expression, ExprType};
 use super::statement::{self, Statement, StmtType};
+use super::function::UserFunction;
+use super::symbol::Symbol;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum AST {
     Expression(expression::Expression),
     Statement(statement::Statement)
 }
 
-pub fn parse(token_slice: &[Token]) -> CalcResult<AST> {
-    // Check that we have an opening parenthesis
-    let mut tokens = token_slice.iter();
-    match tokens.next() {
-        Some(&Token::LPar) => parse_line(&mut tokens),
-        _ => Err("Parentheses not present or wrongly formatted".into())
+// The tokens of the source, which remember the span of the last token they returned.
+// When recovering from errors, they also collect the syntax errors that were skipped
+struct Tokens<'a> {
+    tokens: slice::Iter<'a, SpannedToken>,
+    span: Span,
+    depth: usize,
+    finished: bool,
+    errors: Option<Vec<CalcError>>
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a Token;
+
+    fn next(&mut self) -> Option<&'a Token> {
+        match self.tokens.next() {
+            Some(t) => {
+                self.span = t.span;
+                match t.token {
+                    Token::LPar => self.depth += 1,
+                    Token::RPar => self.depth = self.depth.saturating_sub(1),
+                    _           => ()
+                }
+                Some(&t.token)
+            }
+            None    => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<'a> Tokens<'a> {
+    fn new(tokens: &'a [SpannedToken], recovering: bool) -> Tokens<'a> {
+        let errors = if recovering { Some(vec![]) } else { None };
+        Tokens { tokens: tokens.iter(), span: Span::default(), depth: 0, finished: false, errors }
+    }
+
+    // The span of the last token that was returned
+    fn span(&self) -> Span {
+        self.span
+    }
+
+    // Records an error and skips the rest of the expression that contains it, which was
+    // opened at the given depth. Without recovery, or at the end of the input, the error
+    // is returned instead
+    fn recover(&mut self, error: CalcError, depth: usize) -> CalcResult<()> {
+        let span = self.span;
+        match self.errors {
+            Some(ref mut errors) if !self.finished => errors.push(error.at(span)),
+            _ => return Err(error)
+        }
+
+        while self.depth >= depth && self.next().is_some() {}
+        Ok(())
+    }
+}
+
+// Parses a program: a sequence of forms, which are atoms like `2` or `pi` and
+// parenthesized expressions or statements
+pub fn parse(token_slice: &[SpannedToken]) -> CalcResult<Vec<AST>> {
+    let mut tokens = Tokens::new(token_slice, false);
+    let program = parse_program(&mut tokens)?;
+    if program.is_empty() {
+        return Err(CalcError::syntax("Nothing to evaluate"));
+    }
+    Ok(program)
+}
+
+// Parses a program without stopping at the first syntax error. Malformed expressions
+// are left out of the program, and the errors found in them are returned
+pub fn parse_recovering(token_slice: &[SpannedToken]) -> (Vec<AST>, Vec<CalcError>) {
+    let mut tokens = Tokens::new(token_slice, true);
+    let program = parse_program(&mut tokens).unwrap_or_default();
+    (program, tokens.errors.unwrap_or_default())
+}
+
+fn parse_program(tokens: &mut Tokens) -> CalcResult<Vec<AST>> {
+    let mut program = vec![];
+    while let Some(token) = tokens.next() {
+        // Errors point at the offending token, or at the start of a form that is never closed
+        let start = tokens.span();
+        match parse_form(token, tokens) {
+            Ok(ast) => program.push(ast),
+            Err(e)  => {
+                let e = e.at(if tokens.finished { start } else { tokens.span() });
+                match tokens.errors {
+                    Some(ref mut errors) => errors.push(e),
+                    None                 => return Err(e)
+                }
+                while tokens.depth > 0 && tokens.next().is_some() {}
+            }
+        }
+    }
+    Ok(program)
+}
+
+fn parse_form(token: &Token, tokens: &mut Tokens) -> CalcResult<AST> {
+    match *token {
+        Token::LPar => parse_line(tokens),
+        Token::RPar => Err(CalcError::syntax("Unexpected ')' without a matching '('")),
+        Token::Operator(op) => {
+            Err(CalcError::syntax(format!("Operator '{}' must be the first element of an expression", op.symbol())))
+        }
+        Token::TKeyword(k) => {
+            Err(CalcError::syntax(format!("Keyword '{:?}' must be the first element of an expression", k)))
+        }
+        _ => Ok(AST::Expression(parse_atom(token, tokens.span()).unwrap()))
     }
 }
 
-fn parse_line<'a, 'b, T: Iterator<Item=&'a Token>>(tokens: &'b mut T) -> CalcResult<AST> {
+fn parse_line(tokens: &mut Tokens) -> CalcResult<AST> {
+    // The span of the opening parenthesis
+    let start = tokens.span();
+
     // Depending on the first token, we parse an expression or a statement
     match tokens.next() {
-        Some(&Token::Operator(op)) => {
-            parse_expression(tokens, ExprType::Operator(op))
-                .map(|e| AST::Expression(e))
+        Some(Token::Operator(op)) => {
+            parse_expression(tokens, ExprType::Operator(*op), start)
+                .map(AST::Expression)
         }
-        Some(&Token::Name(ref func_name)) => {
-            parse_expression(tokens, ExprType::Function(func_name.clone()))
-                .map(|e| AST::Expression(e))
+        Some(&Token::Name(func_name)) => {
+            parse_expression(tokens, ExprType::function(func_name), start)
+                .map(AST::Expression)
         }
         Some(&Token::TKeyword(k)) => {
             match k {
                 Keyword::Set => parse_statement(tokens, StmtType::Assign)
-                           .map(|s| AST::Statement(s)),
+                           .map(AST::Statement),
+                Keyword::Define => parse_statement(tokens, StmtType::Define)
+                           .map(AST::Statement),
                 Keyword::Def => parse_statement(tokens, StmtType::FuncDef)
-                           .map(|s| AST::Statement(s))
+                           .map(AST::Statement),
+                Keyword::Lambda => parse_lambda(tokens, start)
+                           .map(AST::Expression),
+                Keyword::Let => parse_let(tokens, start)
+                           .map(AST::Expression),
+                Keyword::Begin => parse_begin(tokens, start)
+                           .map(AST::Expression),
+                Keyword::Cond => parse_cond(tokens, start)
+                           .map(AST::Expression),
+                Keyword::Case => parse_case(tokens, start)
+                           .map(AST::Expression),
+                Keyword::Else => Err(CalcError::syntax("An else clause can only be part of a cond or case")),
+                Keyword::Matrix => parse_matrix(tokens, start)
+                           .map(AST::Expression)
             }
         }
-        _ => Err("Invalid first token".into())
+        None => Err(CalcError::syntax("Unable to find last parentheses of expression")),
+        _    => Err(CalcError::syntax("Invalid first token"))
     }
 }
 
-fn parse_whole_expression<'a, 'b, T: Iterator<Item=&'a Token>>(tokens: &'b mut T)
+fn parse_whole_expression(tokens: &mut Tokens)
         -> CalcResult<Expression>
 {
+    let start = tokens.span();
     match tokens.next() {
         Some(&Token::Operator(op)) => {
-            parse_expression(tokens, ExprType::Operator(op))
+            parse_expression(tokens, ExprType::Operator(op), start)
+        }
+        Some(&Token::Name(func_name)) => {
+            parse_expression(tokens, ExprType::function(func_name), start)
+        }
+        Some(&Token::TKeyword(Keyword::Set)) => {
+            parse_binding(tokens, StmtType::Assign, start)
+        }
+        Some(&Token::TKeyword(Keyword::Define)) => {
+            parse_binding(tokens, StmtType::Define, start)
+        }
+        Some(&Token::TKeyword(Keyword::Lambda)) => {
+            parse_lambda(tokens, start)
+        }
+        Some(&Token::TKeyword(Keyword::Let)) => {
+            parse_let(tokens, start)
+        }
+        Some(&Token::TKeyword(Keyword::Begin)) => {
+            parse_begin(tokens, start)
         }
-        Some(&Token::Name(ref func_name)) => {
-            parse_expression(tokens, ExprType::Function(func_name.clone()))
+        Some(&Token::TKeyword(Keyword::Cond)) => {
+            parse_cond(tokens, start)
         }
-        _ => Err("Invalid first token".into())
+        Some(&Token::TKeyword(Keyword::Case)) => {
+            parse_case(tokens, start)
+        }
+        Some(&Token::TKeyword(Keyword::Else)) => {
+            Err(CalcError::syntax("An else clause can only be part of a cond or case"))
+        }
+        Some(&Token::TKeyword(Keyword::Matrix)) => {
+            parse_matrix(tokens, start)
+        }
+        None => Err(CalcError::syntax("Unable to find last parentheses of expression")),
+        _    => Err(CalcError::syntax("Invalid first token"))
     }
 }
 
-fn parse_expression<'a, 'b, T: Iterator<Item=&'a Token>>(tokens: &'b mut T, top_expr: ExprType)
+fn parse_expression(tokens: &mut Tokens, top_expr: ExprType, start: Span)
         -> CalcResult<Expression>
 {
+    // We make a new Expression based on the Expression type and the arguments
+    let args = parse_args(tokens)?;
+    Ok(expression::Expression{ expr_type: top_expr, args, span: start.to(tokens.span()) })
+}
+
+// Parses the arguments of an expression, up to and including its closing parenthesis
+fn parse_args(tokens: &mut Tokens) -> CalcResult<Vec<Expression>> {
     // Here we will save the arguments of the expression
     let mut args: Vec<Expression> = vec![];
 
     // Go through the restant tokens and translate the arguments into structs
     loop {
-        let token = tokens.next();
-        if token.is_none() { return Err("Unable to find last parentheses of expression".into()) }
-        match *token.unwrap() {
+        let token = match tokens.next() {
+            Some(t) => t,
+            None    => return Err(CalcError::syntax("Unable to find last parentheses of expression"))
+        };
+        match *token {
             // Here begins a sub expression
             Token::LPar => {
                 // Call this function recursively to get the AST of the sub expression
-                let sub_expr = try!(parse_whole_expression(tokens));
-                args.push(sub_expr);
+                let depth = tokens.depth;
+                match parse_whole_expression(tokens) {
+                    Ok(sub_expr) => args.push(sub_expr),
+                    Err(e)       => tokens.recover(e, depth)?
+                }
             }
             // Here ends an expression
             Token::RPar => {
-                // We make a new Expression based on the Expression type and the arguments
-                return Ok(expression::Expression{ expr_type: top_expr, args: args });
+                return Ok(args);
             }
             // Operator
             Token::Operator(op) => {
-                return Err(format!("Operator '{:?}' in wrong position", op).into());
-            }
-            // A number to be used as argument for an Expression
-            Token::Literal(x) => {
-                let number = Expression::from_type(ExprType::Number(x));
-                args.push(number);
-            }
-            // The name of a constant or variable
-            Token::Name(ref name) => {
-                let var = Expression::from_type(ExprType::Variable(name.clone()));
-                args.push(var);
+                return Err(CalcError::syntax(format!("Operator '{}' in wrong position", op.symbol())));
             }
             Token::TKeyword(k) => {
-                return Err(format!("Keyword '{:?}' in wrong position", k).into());
+                return Err(CalcError::syntax(format!("Keyword '{:?}' in wrong position", k)));
+            }
+            // A number, string or name to be used as argument for an Expression
+            _ => {
+                args.push(parse_atom(token, tokens.span()).unwrap());
             }
         }
     }
 }
 
-fn parse_statement<'a, 'b, T: Iterator<Item=&'a Token>>(tokens: &'b mut T, top_stmt: StmtType)
+fn parse_statement(tokens: &mut Tokens, top_stmt: StmtType)
         -> CalcResult<Statement>
 {
     match top_stmt {
-        StmtType::FuncDef => panic!("Function definition is not yet implemented"), // FIXME
-        StmtType::Assign  => {
-            parse_assign(tokens)
+        StmtType::FuncDef => {
+            parse_funcdef(tokens)
+        }
+        StmtType::Assign | StmtType::Define => {
+            parse_assign(tokens, top_stmt)
         }
     }
 }
 
-fn parse_assign<'a, 'b, T: Iterator<Item=&'a Token>>(tokens: &'b mut T) -> CalcResult<Statement> {
+// Inside an expression, `set` and `define` bind a name in the scope they are evaluated in
+fn parse_binding(tokens: &mut Tokens, stmt_type: StmtType, start: Span) -> CalcResult<Expression> {
+    let s = parse_assign(tokens, stmt_type)?;
+    let expr_type = match s.stmt_type {
+        StmtType::Define => ExprType::Define(s.name),
+        _                => ExprType::Set(s.name)
+    };
+    Ok(Expression { expr_type, args: vec![s.rhs], span: start.to(tokens.span()) })
+}
+
+fn parse_assign(tokens: &mut Tokens, stmt_type: StmtType) -> CalcResult<Statement> {
     // The first token will be the name of the variable
     let name = match tokens.next() {
-        Some(&Token::Name(ref n)) => n.clone(),
-        Some(ref t)        => return Err(format!("Unexpected {:?} expecting Name", t).into()),
-        None               => return Err("Unexpected end of token-stream".into())
+        Some(&Token::Name(n)) => n,
+        Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting Name", t))),
+        None               => return Err(CalcError::syntax("Unexpected end of token-stream"))
     };
     
-    // The second token will be a number or a sub-expression
-    let rhs = match tokens.next() {
-        Some(&Token::LPar)       => try!(parse_whole_expression(tokens)),
-        Some(&Token::Literal(x)) => Expression::from_type(ExprType::Number(x)),
-        Some(ref t)       => return Err(format!("Unexpected {:?} expecting LPar or Literal", t).into()),
-        None              => return Err("Unexpected end of token-stream".into())
+    // The second token will be an atom or a sub-expression
+    let rhs = parse_body(tokens)?;
+
+    // Finally, the assignment must be closed
+    match tokens.next() {
+        Some(&Token::RPar) => (),
+        Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting RPar", t))),
+        None               => return Err(CalcError::syntax("Unable to find last parentheses of assignment"))
+    }
+
+    Ok(Statement { stmt_type, name, params: vec![], rhs })
+}
+
+fn parse_funcdef(tokens: &mut Tokens) -> CalcResult<Statement> {
+    // The signature is enclosed in parentheses: (name param1 param2 ...)
+    expect_lpar(tokens)?;
+    let name = match tokens.next() {
+        Some(&Token::Name(n)) => n,
+        Some(t)               => return Err(CalcError::syntax(format!("Unexpected {:?} expecting Name", t))),
+        None                  => return Err(CalcError::syntax("Unexpected end of token-stream"))
     };
-    
-    Ok(Statement { stmt_type: StmtType::Assign, name: name, rhs: rhs })
+    let params = parse_params(tokens)?;
+    let body = parse_body(tokens)?;
+
+    // Finally, the definition must be closed
+    match tokens.next() {
+        Some(&Token::RPar) => (),
+        Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting RPar", t))),
+        None               => return Err(CalcError::syntax("Unable to find last parentheses of function definition"))
+    }
+
+    Ok(Statement { stmt_type: StmtType::FuncDef, name, params, rhs: body })
+}
+
+fn parse_lambda(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    // The parameters are enclosed in parentheses: (lambda (param1 param2 ...) body)
+    expect_lpar(tokens)?;
+    let params = parse_params(tokens)?;
+    let body = parse_body(tokens)?;
+
+    match tokens.next() {
+        Some(&Token::RPar) => (),
+        Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting RPar", t))),
+        None               => return Err(CalcError::syntax("Unable to find last parentheses of lambda"))
+    }
+
+    let function = UserFunction::new(Symbol::intern("lambda"), params, body)?;
+    Ok(Expression::from_type(ExprType::Lambda(Rc::new(function)), start.to(tokens.span())))
+}
+
+fn parse_let(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    // The bindings are enclosed in parentheses: (let ((name1 value1) (name2 value2) ...) body...)
+    expect_lpar(tokens)?;
+    let mut names = vec![];
+    let mut args = vec![];
+    loop {
+        match tokens.next() {
+            Some(&Token::LPar) => (),
+            Some(&Token::RPar) => break,
+            Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting a binding", t))),
+            None               => return Err(CalcError::syntax("Unable to find last parentheses of let"))
+        }
+
+        let binding = parse_assign(tokens, StmtType::Define)?;
+        names.push(binding.name);
+        args.push(binding.rhs);
+    }
+
+    // The value of the body is the value of its last form
+    let body = parse_args(tokens)?;
+    if body.is_empty() {
+        return Err(CalcError::syntax("A let requires a body"));
+    }
+    args.extend(body);
+    Ok(Expression { expr_type: ExprType::Let(names), args, span: start.to(tokens.span()) })
+}
+
+fn parse_begin(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    let forms = parse_args(tokens)?;
+    if forms.is_empty() {
+        return Err(CalcError::syntax("A begin requires at least one form"));
+    }
+    Ok(Expression { expr_type: ExprType::Begin, args: forms, span: start.to(tokens.span()) })
+}
+
+fn parse_cond(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    // Each clause is a test and a value: (cond (test1 value1) (test2 value2) ... (else value))
+    let mut args = vec![];
+    parse_clauses(tokens, &mut args, "cond")?;
+    Ok(Expression { expr_type: ExprType::Cond, args, span: start.to(tokens.span()) })
+}
+
+fn parse_case(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    // The key is compared with the first element of each clause: (case key (1 value1) ... (else value))
+    let mut args = vec![parse_body(tokens)?];
+    parse_clauses(tokens, &mut args, "case")?;
+    Ok(Expression { expr_type: ExprType::Case, args, span: start.to(tokens.span()) })
+}
+
+// Adds both elements of each clause to the arguments, and the value of the else clause last
+fn parse_clauses(tokens: &mut Tokens, args: &mut Vec<Expression>, form: &str) -> CalcResult<()> {
+    let mut clauses = 0;
+    let mut has_else = false;
+    loop {
+        match tokens.next() {
+            Some(&Token::LPar) if has_else => {
+                return Err(CalcError::syntax(format!("The else clause must be the last one of a {}", form)))
+            }
+            Some(&Token::LPar) => (),
+            Some(&Token::RPar) => break,
+            Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting a clause", t))),
+            None               => return Err(CalcError::syntax(format!("Unable to find last parentheses of {}", form)))
+        }
+
+        match tokens.next() {
+            Some(&Token::TKeyword(Keyword::Else)) => has_else = true,
+            Some(t)                               => args.push(parse_operand(t, tokens)?),
+            None                                  => return Err(CalcError::syntax("Unexpected end of token-stream"))
+        }
+        args.push(parse_body(tokens)?);
+        clauses += 1;
+
+        match tokens.next() {
+            Some(&Token::RPar) => (),
+            Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting RPar", t))),
+            None               => return Err(CalcError::syntax("Unable to find last parentheses of clause"))
+        }
+    }
+
+    if clauses == 0 {
+        return Err(CalcError::syntax(format!("A {} requires at least one clause", form)));
+    }
+    Ok(())
+}
+
+fn parse_matrix(tokens: &mut Tokens, start: Span) -> CalcResult<Expression> {
+    // Each row is enclosed in parentheses: (matrix (1 2) (3 4))
+    let mut elements = vec![];
+    let mut cols = None;
+    let mut rows = 0;
+    loop {
+        match tokens.next() {
+            Some(&Token::LPar) => (),
+            Some(&Token::RPar) => break,
+            Some(t)            => return Err(CalcError::syntax(format!("Unexpected {:?} expecting a matrix row", t))),
+            None               => return Err(CalcError::syntax("Unable to find last parentheses of matrix"))
+        }
+
+        let row = parse_args(tokens)?;
+        rows += 1;
+        match cols {
+            None if row.is_empty()       => return Err(CalcError::syntax("Matrix rows cannot be empty")),
+            None                         => cols = Some(row.len()),
+            Some(n) if n != row.len()    => {
+                return Err(CalcError::syntax(format!("Row {} of the matrix has {} elements, expected {}", rows, row.len(), n)));
+            }
+            Some(_)                      => ()
+        }
+        elements.extend(row);
+    }
+
+    match cols {
+        Some(cols) => {
+            Ok(Expression { expr_type: ExprType::Matrix(cols), args: elements, span: start.to(tokens.span()) })
+        }
+        None       => Err(CalcError::syntax("A matrix requires at least one row"))
+    }
+}
+
+fn expect_lpar(tokens: &mut Tokens) -> CalcResult<()> {
+    match tokens.next() {
+        Some(&Token::LPar) => Ok(()),
+        Some(t)            => Err(CalcError::syntax(format!("Unexpected {:?} expecting LPar", t))),
+        None               => Err(CalcError::syntax("Unexpected end of token-stream"))
+    }
+}
+
+// Parses parameter names up to the closing parenthesis
+fn parse_params(tokens: &mut Tokens) -> CalcResult<Vec<Symbol>> {
+    let mut params = vec![];
+    loop {
+        match tokens.next() {
+            Some(&Token::Name(p)) => params.push(p),
+            Some(&Token::RPar)    => return Ok(params),
+            Some(t)               => return Err(CalcError::syntax(format!("Unexpected {:?} expecting Name or RPar", t))),
+            None                  => return Err(CalcError::syntax("Unexpected end of token-stream"))
+        }
+    }
+}
+
+// The body of a function or assignment can be a sub-expression or an atom
+fn parse_body(tokens: &mut Tokens) -> CalcResult<Expression> {
+    match tokens.next() {
+        Some(t) => parse_operand(t, tokens),
+        None    => Err(CalcError::syntax("Unexpected end of token-stream"))
+    }
+}
+
+// Parses an atom or a sub-expression, starting from its first token
+fn parse_operand(token: &Token, tokens: &mut Tokens) -> CalcResult<Expression> {
+    match *token {
+        Token::LPar => parse_whole_expression(tokens),
+        _           => {
+            parse_atom(token, tokens.span()).ok_or_else(|| {
+                CalcError::syntax(format!("Unexpected {:?} expecting LPar, Literal, Str or Name", token))
+            })
+        }
+    }
+}
+
+// Atoms are numbers, strings and names of constants or variables
+fn parse_atom(token: &Token, span: Span) -> Option<Expression> {
+    let expr_type = match *token {
+        Token::Literal(ref x) => ExprType::Number(x.clone()),
+        Token::Str(ref s)     => ExprType::Str(s.clone()),
+        Token::Name(n)        => ExprType::variable(n),
+        _                     => return None
+    };
+    Some(Expression::from_type(expr_type, span))
 }
@@ -0,0 +1,93 @@
+/*
+
+Implements compiled programs. A program is checked, optimized and compiled once, and can then
+be run many times, like a formula that is evaluated with different values of its variables.
+
+*/
+
+use std::rc::Rc;
+
+use super::CalcResult;
+use super::compiler::{self, Chunk};
+use super::environment::Environment;
+use super::expression::Expression;
+use super::function::UserFunction;
+use super::number::Number;
+use super::optimize;
+use super::parser::AST;
+use super::statement::StmtType;
+use super::symbol::Symbol;
+use super::value::Value;
+use super::vm::{self, Evaluator};
+
+pub struct Program {
+    forms: Vec<Form>
+}
+
+// Expressions are kept next to their bytecode, so a program can be run with either evaluator
+enum Form {
+    Expression(Expression, Chunk),
+    Assign(Symbol, Expression, Chunk),
+    Define(Symbol, Expression, Chunk),
+    FuncDef(Rc<UserFunction>)
+}
+
+impl Program {
+    // Builds a program out of forms that passed the semantic analysis
+    pub fn new(program: Vec<AST>, env: &Environment) -> CalcResult<Program> {
+        let mut forms = vec![];
+        for mut ast in program {
+            optimize::optimize(&mut ast, env);
+            forms.push(match ast {
+                AST::Expression(e) => {
+                    let code = compiler::compile(&e, &[], env.numeric_mode());
+                    Form::Expression(e, code)
+                }
+                AST::Statement(s) => match s.stmt_type {
+                    StmtType::Assign => {
+                        let code = compiler::compile(&s.rhs, &[], env.numeric_mode());
+                        Form::Assign(s.name, s.rhs, code)
+                    }
+                    StmtType::Define => {
+                        let code = compiler::compile(&s.rhs, &[], env.numeric_mode());
+                        Form::Define(s.name, s.rhs, code)
+                    }
+                    StmtType::FuncDef => Form::FuncDef(Rc::new(UserFunction::new(s.name, s.params, s.rhs)?))
+                }
+            });
+        }
+        Ok(Program { forms })
+    }
+
+    // Runs the forms in order and returns the result of the last one. Statements give 0
+    pub fn run(&self, env: &mut Environment) -> CalcResult {
+        let mut result = Value::Number(Number::from(0));
+        for form in &self.forms {
+            result = match *form {
+                Form::Expression(ref e, ref code) => eval(e, code, env)?,
+                Form::Assign(name, ref e, ref code) => {
+                    let value = eval(e, code, env)?;
+                    env.set_var(name, value);
+                    Value::Number(Number::from(0))
+                }
+                Form::Define(name, ref e, ref code) => {
+                    let value = eval(e, code, env)?;
+                    env.define_var(name, value);
+                    Value::Number(Number::from(0))
+                }
+                Form::FuncDef(ref f) => {
+                    env.set_fn(f.clone());
+                    Value::Number(Number::from(0))
+                }
+            };
+        }
+        Ok(result)
+    }
+}
+
+fn eval(e: &Expression, code: &Chunk, env: &Environment) -> CalcResult {
+    match env.evaluator() {
+        Evaluator::Tree     => e.eval(env),
+        Evaluator::Bytecode => vm::execute(code, env, &[])
+    }
+}
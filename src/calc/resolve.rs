@@ -0,0 +1,46 @@
+/*
+
+Implements the resolution pass, which binds the variables in the body of a function that name
+one of its parameters to the position of the parameter, so the bytecode reads the argument
+directly instead of looking the name up in the environment.
+
+Lambdas in the body have parameters of their own and are resolved when they are created. The
+parameters of the enclosing function are looked up in the scope they captured from them.
+Parameters that the body changes with `set` or `define` are always looked up in the scope
+too, since the arguments the function was called with are no longer their values, and so are
+the names a `let` block binds again inside it.
+
+*/
+
+use super::expression::{Binding, ExprType, Expression};
+use super::symbol::Symbol;
+
+pub fn resolve(body: &mut Expression, params: &[Symbol]) {
+    let mut assigned = vec![];
+    body.assigned(&mut assigned);
+    let slots: Vec<_> = params.iter().map(|p| if assigned.contains(p) { None } else { Some(*p) }).collect();
+    bind(body, &slots);
+}
+
+// `slots` has the name of each parameter that can be read from its slot
+fn bind(e: &mut Expression, slots: &[Option<Symbol>]) {
+    match e.expr_type {
+        ExprType::Variable(name, ref mut binding) => {
+            if let Some(slot) = slots.iter().position(|&p| p == Some(name)) {
+                *binding = Binding::Local(slot);
+            }
+        }
+        ExprType::Let(ref names) => {
+            let inner: Vec<_> = slots.iter().map(|p| p.filter(|p| !names.contains(p))).collect();
+            for arg in &mut e.args {
+                bind(arg, &inner);
+            }
+            return;
+        }
+        _ => ()
+    }
+
+    for arg in &mut e.args {
+        bind(arg, slots);
+    }
+}
@@ -8,27 +8,49 @@ of tokens.
 use std::char;
 
 use super::CalcResult;
+use super::error::CalcError;
 use super::operator::Operator;
 use super::buffer::Buffer;
+use super::number::Number;
+use super::span::{Locator, Span};
+use super::suggest;
+use super::symbol::Symbol;
 
 #[derive(Clone, Debug)]
 pub enum Token {
-    Literal(f64),               // A number
+    Literal(Number),            // A number
+    Str(String),                // A string, with its escapes already replaced
     LPar,                       // A left parenthesis
     RPar,                       // A right parenthesis
     Operator(Operator),         // An operator
-    Name(String),               // A name
+    Name(Symbol),               // A name
     TKeyword(Keyword),          // A keyword
 }
 
 #[derive(Clone, Copy, Debug)]
 pub enum Keyword {
     Set,
-    Def
+    Define,
+    Def,
+    Lambda,
+    Let,
+    Begin,
+    Cond,
+    Case,
+    Else,
+    Matrix
 }
 
-pub fn scan(s: &str) -> CalcResult<Vec<Token>> {
+// A token together with the part of the source it was read from
+#[derive(Clone, Debug)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span
+}
+
+pub fn scan(s: &str) -> CalcResult<Vec<SpannedToken>> {
     let mut buf = Buffer::new(s.chars());
+    let locator = Locator::new(s);
     let mut tokens = Vec::new();
 
     while !buf.is_empty() {
@@ -38,69 +60,167 @@ pub fn scan(s: &str) -> CalcResult<Vec<Token>> {
             continue;
         }
 
-        // -----------
-        // Parentheses
-        // -----------
-        let token = match buf.peek().unwrap() {
-            '(' => Some(Token::LPar),
-            ')' => Some(Token::RPar),
-            _   => None
-        };
-        if token.is_some() {
-            tokens.push(token.unwrap());
-            buf.pop();
+        // --------
+        // Comments
+        // --------
+        let start = buf.position();
+
+        // Line comments start with `;` and end at the end of the line
+        if buf.peek() == Some(';') {
+            buf.take_until(|&c| c == '\n');
             continue;
         }
 
-        // ---------
-        // Operators
-        // ---------
-
-        // We know that there is at least one word
-        let word: String = buf.take_until(|&c| c.is_whitespace() || c == ')' || c == '(').into_iter().collect();
-
-        // Operators are always separated by whitespace from the restant tokens
-        match Operator::from_str(&word) {
-            Some(op_type) => {
-                tokens.push(Token::Operator(op_type));
+        // Block comments are enclosed in `#|` and `|#`, and can be nested
+        if buf.peek() == Some('#') {
+            buf.pop();
+            if buf.peek() == Some('|') {
+                buf.pop();
+                skip_block_comment(&mut buf).map_err(|e| e.at(locator.span(start, buf.position())))?;
                 continue;
             }
-            _   => { }
+            buf.push('#');
+        }
+
+        let token = scan_token(&mut buf);
+        let span = locator.span(start, buf.position());
+        tokens.push(SpannedToken { token: token.map_err(|e| e.at(span))?, span });
+    }
+
+    Ok(tokens)
+}
+
+// Reads the token at the current position, which is not whitespace
+fn scan_token<T: Iterator<Item=char>>(buf: &mut Buffer<char, T>) -> CalcResult<Token> {
+    // -----------
+    // Parentheses
+    // -----------
+    let token = match buf.peek().unwrap() {
+        '(' => Some(Token::LPar),
+        ')' => Some(Token::RPar),
+        _   => None
+    };
+    if let Some(token) = token {
+        buf.pop();
+        return Ok(token);
+    }
+
+    // -------
+    // Strings
+    // -------
+    if buf.peek() == Some('"') {
+        buf.pop();
+        return scan_string(buf).map(Token::Str);
+    }
+
+    // ---------
+    // Operators
+    // ---------
+
+    // We know that there is at least one word
+    let word: String = buf.take_until(|&c| c.is_whitespace() || c == ')' || c == '(' || c == '"' || c == ';')
+                          .into_iter().collect();
+
+    // Operators are always separated by whitespace from the restant tokens
+    if let Some(op_type) = Operator::from_str(&word) {
+        return Ok(Token::Operator(op_type));
+    }
+
+    // -----------------
+    // Literals and names
+    // -----------------
+
+    // We know that a word has at least one character, so we can safely unwrap it
+    let c = word.chars().next().unwrap();
+
+    // A literal token
+    if char::is_digit(c, 10) || c == '-' {
+        return match Number::from_str(&word) {
+            Some(x) => Ok(Token::Literal(x)),
+            _       => Err(CalcError::lexical(format!("Invalid number '{}'", word)))
         };
+    }
 
-        // -----------------
-        // Literals and names
-        // -----------------
-
-        // We know that a word has at least one character, so we can safely unwrap it
-        let c = word.chars().next().unwrap();
-
-        // A literal token
-        if char::is_digit(c, 10) || c == '-' {
-            match word.parse::<f64>() {
-                Ok(x) => {
-                    tokens.push(Token::Literal(x));
-                    continue;
-                }
-                _ => { return Err(format!("Invalid number '{}'", word).into()); }
-            }
-        }
+    // A name token
+    if c.is_alphabetic() {
+        // It can be a keyword or a name
+        return Ok(match &word[..] {
+            "set" => Token::TKeyword(Keyword::Set),
+            "define" => Token::TKeyword(Keyword::Define),
+            "def" => Token::TKeyword(Keyword::Def),
+            "lambda" => Token::TKeyword(Keyword::Lambda),
+            "let" => Token::TKeyword(Keyword::Let),
+            "begin" => Token::TKeyword(Keyword::Begin),
+            "cond" => Token::TKeyword(Keyword::Cond),
+            "case" => Token::TKeyword(Keyword::Case),
+            "else" => Token::TKeyword(Keyword::Else),
+            "matrix" => Token::TKeyword(Keyword::Matrix),
+            _     => Token::Name(Symbol::intern(&word))
+        });
+    }
 
-        // A name token
-        if c.is_alphabetic() {
-            // It can be a keyword or a name
-            match &word[..] {
-                "set" => tokens.push(Token::TKeyword(Keyword::Set)),
-                "def" => tokens.push(Token::TKeyword(Keyword::Def)),
-                _     => tokens.push(Token::Name(word))
-            }
+    // This point is only reached when no token has been matched, which is often a mistyped operator
+    match suggest::closest(&word, Operator::symbols().collect()) {
+        Some(op) => Err(CalcError::lexical(format!("Unrecognized token '{}', did you mean '{}'?", word, op))),
+        None     => Err(CalcError::lexical(format!("Unrecognized token '{}'", word)))
+    }
+}
 
-            continue;
+// Skips the rest of a block comment, after its opening `#|`
+fn skip_block_comment<T: Iterator<Item=char>>(buf: &mut Buffer<char, T>) -> CalcResult<()> {
+    let mut depth = 1;
+    while depth > 0 {
+        match buf.pop() {
+            Some('|') if buf.peek() == Some('#') => {
+                buf.pop();
+                depth -= 1;
+            }
+            Some('#') if buf.peek() == Some('|') => {
+                buf.pop();
+                depth += 1;
+            }
+            Some(_) => (),
+            None    => return Err(CalcError::unterminated("Unterminated block comment"))
         }
+    }
+    Ok(())
+}
 
-        // This point is only reached when no token has been matched
-        return Err(format!("Unrecognized token '{}'", word).into());
+// Reads the rest of a string literal, after its opening quote
+fn scan_string<T: Iterator<Item=char>>(buf: &mut Buffer<char, T>) -> CalcResult<String> {
+    let mut result = String::new();
+    loop {
+        match buf.pop() {
+            Some('"')  => return Ok(result),
+            Some('\\') => result.push(scan_escape(buf)?),
+            Some(c)    => result.push(c),
+            None       => return Err(CalcError::unterminated("Unterminated string literal"))
+        }
     }
+}
 
-    Ok(tokens)
+// Supports the escapes `\"`, `\\`, `\n`, `\t`, `\r`, `\0` and `\u{...}`
+fn scan_escape<T: Iterator<Item=char>>(buf: &mut Buffer<char, T>) -> CalcResult<char> {
+    match buf.pop() {
+        Some('"')  => Ok('"'),
+        Some('\\') => Ok('\\'),
+        Some('n')  => Ok('\n'),
+        Some('t')  => Ok('\t'),
+        Some('r')  => Ok('\r'),
+        Some('0')  => Ok('\0'),
+        Some('u')  => {
+            if buf.pop() != Some('{') {
+                return Err(CalcError::lexical("Expected '{' after '\\u' in string literal"));
+            }
+            let hex: String = buf.take_until(|&c| c == '}' || c == '"').into_iter().collect();
+            if buf.pop() != Some('}') {
+                return Err(CalcError::lexical("Unterminated '\\u{...}' escape in string literal"));
+            }
+            u32::from_str_radix(&hex, 16).ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| CalcError::lexical(format!("Invalid unicode escape '\\u{{{}}}'", hex)))
+        }
+        Some(c)    => Err(CalcError::lexical(format!("Invalid escape sequence '\\{}' in string literal", c))),
+        None       => Err(CalcError::unterminated("Unterminated string literal"))
+    }
 }
@@ -0,0 +1,69 @@
+/*
+
+Implements source spans, which tell where a token or an expression comes
+from, so errors can point at the exact place of the problem.
+
+*/
+
+// A range of the source, with byte offsets and the line and column (counted
+// in characters, starting at 1) where it begins
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize
+}
+
+impl Span {
+    // The span that covers this one and everything up to the end of the other
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+
+    // Prints the line of the source where the span begins, with the span underlined
+    pub fn render(&self, source: &str) -> String {
+        let line_start = source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[self.start..].find('\n').map_or(source.len(), |i| self.start + i);
+        let line = &source[line_start..line_end];
+
+        // Tabs are kept in the padding, so the carets line up with the text above them
+        let padding: String = source[line_start..self.start].chars()
+                                                            .map(|c| if c == '\t' { '\t' } else { ' ' })
+                                                            .collect();
+        let width = source[self.start..self.end.min(line_end)].chars().count().max(1);
+
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        format!("{} | {}\n{} | {}{}", number, line, gutter, padding, "^".repeat(width))
+    }
+}
+
+// Computes the spans of a source, given as character positions
+pub struct Locator {
+    // The byte offset, line and column of every character, and of the end of the source
+    positions: Vec<(usize, usize, usize)>
+}
+
+impl Locator {
+    pub fn new(source: &str) -> Locator {
+        let mut positions = Vec::with_capacity(source.len() + 1);
+        let (mut line, mut column) = (1, 1);
+        for (offset, c) in source.char_indices() {
+            positions.push((offset, line, column));
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        positions.push((source.len(), line, column));
+        Locator { positions }
+    }
+
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        let (start, line, column) = self.positions[start];
+        Span { start, end: self.positions[end].0, line, column }
+    }
+}
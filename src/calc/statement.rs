@@ -1,29 +1,15 @@
-use super::CalcResult;
 use super::expression::Expression;
-use super::environment::Environment;
+use super::symbol::Symbol;
 
 pub enum StmtType {
-    Assign,
+    Assign,     // `set`, which updates the nearest binding of the name
+    Define,     // `define`, which always binds the name in the current scope
     FuncDef,
 }
 
 pub struct Statement {
     pub stmt_type: StmtType,
-    pub name: String,
+    pub name: Symbol,
+    pub params: Vec<Symbol>,
     pub rhs: Expression
 }
-
-impl Statement {
-    pub fn exec(&self, env: &mut Environment) -> CalcResult<()> {
-        match self.stmt_type {
-            StmtType::Assign => {
-                let rhs = try!(self.rhs.eval(env));
-                env.set_var(&self.name, rhs);
-                Ok(())
-            }
-            StmtType::FuncDef => {
-                panic!("Not implemented")
-            }
-        }
-    }
-}
@@ -0,0 +1,79 @@
+/*
+
+Implements the suggestions shown for unknown names, based on the edit distance
+between the unknown name and the names the calculator knows about.
+
+*/
+
+use std::cmp;
+
+use super::constant::Constant;
+use super::environment::Environment;
+use super::error::CalcError;
+use super::function::Function;
+use super::operator::Operator;
+
+// Creates the error for an unknown name, suggesting the closest builtin or user-defined name.
+// `extra` are names that are not in the environment yet, like the ones a program defines
+pub fn unknown_name<I>(name: &str, env: &Environment, extra: I) -> CalcError
+where I: IntoIterator<Item=&'static str>
+{
+    let mut candidates: Vec<&str> = Function::names().chain(Constant::names()).chain(Operator::symbols()).collect();
+    candidates.extend(env.names());
+    candidates.extend(extra);
+    CalcError::unknown_name(name).with_suggestion(closest(name, candidates))
+}
+
+// Finds the candidate that is most likely what was meant. The allowed number of edits grows
+// with the length of the name, and replacing a whole name doesn't count as a typo. Among
+// equally distant candidates, the ones with more characters in common are preferred, and
+// then the ones starting like the name
+pub fn closest<'a>(name: &str, candidates: Vec<&'a str>) -> Option<&'a str> {
+    let length = name.chars().count();
+    let max_distance = cmp::max(1, length / 3);
+    candidates.into_iter()
+              .filter(|&c| c != name)
+              .map(|c| (distance(name, c), c))
+              .filter(|&(d, c)| d <= max_distance && d < cmp::max(length, c.chars().count()))
+              .min_by_key(|&(d, c)| {
+                  let different_start = name.chars().next() != c.chars().next();
+                  (d, cmp::Reverse(common_chars(name, c)), different_start, c)
+              })
+              .map(|(_, c)| c)
+}
+
+// The number of characters two strings share, regardless of their position
+fn common_chars(a: &str, b: &str) -> usize {
+    let mut rest: Vec<char> = b.chars().collect();
+    a.chars().filter(|c| match rest.iter().position(|x| x == c) {
+        Some(i) => { rest.swap_remove(i); true }
+        None    => false
+    }).count()
+}
+
+// The number of insertions, deletions, substitutions and transpositions of adjacent
+// characters needed to turn one string into the other
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // d[i][j] is the distance between the first i chars of a and the first j chars of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = cmp::min(cmp::min(d[i - 1][j] + 1, d[i][j - 1] + 1), d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = cmp::min(d[i][j], d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
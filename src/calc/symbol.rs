@@ -0,0 +1,73 @@
+/*
+
+Implements interned symbols, which stand for names in tokens, expressions and environments.
+
+Every name is stored once and represented by a small id, so names are compared and looked up
+without touching their characters. The text of a symbol lives as long as the program, since
+the set of names used in a session is small.
+
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, RwLock};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+struct Interner {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, Symbol>
+}
+
+// Names are looked up far more often than new ones are added, so readers don't block each other
+static INTERNER: LazyLock<RwLock<Interner>> = LazyLock::new(|| {
+    RwLock::new(Interner { names: vec![], ids: HashMap::new() })
+});
+
+impl Symbol {
+    // Returns the symbol of a name, creating it the first time the name is seen
+    pub fn intern(name: &str) -> Symbol {
+        if let Some(&symbol) = INTERNER.read().unwrap().ids.get(name) {
+            return symbol;
+        }
+
+        // Another thread may have added the name since it was looked up
+        let mut interner = INTERNER.write().unwrap();
+        if let Some(&symbol) = interner.ids.get(name) {
+            return symbol;
+        }
+
+        let symbol = Symbol(interner.names.len() as u32);
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        interner.names.push(name);
+        interner.ids.insert(name, symbol);
+        symbol
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.read().unwrap().names[self.index()]
+    }
+
+    // Ids are given in order starting from 0, so they can be used to index tables
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn from_index(index: usize) -> Symbol {
+        Symbol(index as u32)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Shows the name, so tokens and expressions print like they did with strings
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
@@ -0,0 +1,288 @@
+/*
+
+Implements physical units and dimensional analysis. Quantities are stored
+in SI base units, so `(* 3 km)` is kept as 3000 m, together with the
+exponent of each base unit.
+
+*/
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+use super::CalcResult;
+use super::error::CalcError;
+use super::number::{Number, NumericMode};
+use super::value::Value;
+
+const BASE_UNITS: [&str; 7] = ["m", "kg", "s", "A", "K", "mol", "cd"];
+
+// The exponents of the SI base units, in the order of `BASE_UNITS`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Dimension([i8; 7]);
+
+impl Dimension {
+    pub fn is_dimensionless(&self) -> bool {
+        self.0.iter().all(|&e| e == 0)
+    }
+
+    fn scale(&self, factor: i8) -> CalcResult<Dimension> {
+        let mut result = *self;
+        for e in &mut result.0 {
+            *e = e.checked_mul(factor).ok_or_else(exponent_overflow)?;
+        }
+        Ok(result)
+    }
+
+    // Returns the dimension halved, if every exponent is even
+    fn halve(&self) -> Option<Dimension> {
+        if self.0.iter().any(|e| e % 2 != 0) {
+            return None;
+        }
+
+        let mut result = *self;
+        for e in &mut result.0 {
+            *e /= 2;
+        }
+        Some(result)
+    }
+
+    // The dimension of a product
+    fn add(&self, other: &Dimension) -> CalcResult<Dimension> {
+        self.combine(other, i8::checked_add)
+    }
+
+    // The dimension of a quotient
+    fn sub(&self, other: &Dimension) -> CalcResult<Dimension> {
+        self.combine(other, i8::checked_sub)
+    }
+
+    fn combine(&self, other: &Dimension, op: fn(i8, i8) -> Option<i8>) -> CalcResult<Dimension> {
+        let mut result = *self;
+        for (e, &o) in result.0.iter_mut().zip(&other.0) {
+            *e = op(*e, o).ok_or_else(exponent_overflow)?;
+        }
+        Ok(result)
+    }
+}
+
+// The exponents of the base units are kept in an i8
+fn exponent_overflow() -> CalcError {
+    CalcError::limit_exceeded(format!("The exponents of units must be between {} and {}", i8::MIN, i8::MAX))
+}
+
+// Prints the dimension as a product of base units, like `m kg s^-2`
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_dimensionless() {
+            return write!(f, "1");
+        }
+
+        let mut first = true;
+        for (name, &exp) in BASE_UNITS.iter().zip(&self.0) {
+            if exp == 0 {
+                continue;
+            }
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+
+            if exp == 1 {
+                write!(f, "{}", name)?;
+            } else {
+                write!(f, "{}^{}", name, exp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A unit of the registry: a factor relative to the SI base units, and a dimension
+struct UnitDef {
+    name: &'static str,
+    factor: (i64, i64),
+    dimension: [i8; 7],
+    prefixable: bool
+}
+
+macro_rules! unit {
+    ($name:expr, $num:tt / $den:tt, $dim:expr, $prefixable:expr) => {
+        UnitDef { name: $name, factor: ($num, $den), dimension: $dim, prefixable: $prefixable }
+    }
+}
+
+static UNITS: [UnitDef; 19] = [
+    // SI base units. The kilogram is obtained by prefixing the gram.
+    // Dimensions are given as exponents of m, kg, s, A, K, mol and cd
+    unit!("m",   1 / 1,     [ 1,  0,  0,  0, 0, 0, 0], true),
+    unit!("g",   1 / 1000,  [ 0,  1,  0,  0, 0, 0, 0], true),
+    unit!("s",   1 / 1,     [ 0,  0,  1,  0, 0, 0, 0], true),
+    unit!("A",   1 / 1,     [ 0,  0,  0,  1, 0, 0, 0], true),
+    unit!("K",   1 / 1,     [ 0,  0,  0,  0, 1, 0, 0], true),
+    unit!("mol", 1 / 1,     [ 0,  0,  0,  0, 0, 1, 0], true),
+    unit!("cd",  1 / 1,     [ 0,  0,  0,  0, 0, 0, 1], true),
+
+    // Derived units
+    unit!("N",   1 / 1,     [ 1,  1, -2,  0, 0, 0, 0], true),
+    unit!("J",   1 / 1,     [ 2,  1, -2,  0, 0, 0, 0], true),
+    unit!("W",   1 / 1,     [ 2,  1, -3,  0, 0, 0, 0], true),
+    unit!("Pa",  1 / 1,     [-1,  1, -2,  0, 0, 0, 0], true),
+    unit!("Hz",  1 / 1,     [ 0,  0, -1,  0, 0, 0, 0], true),
+    unit!("C",   1 / 1,     [ 0,  0,  1,  1, 0, 0, 0], true),
+    unit!("V",   1 / 1,     [ 2,  1, -3, -1, 0, 0, 0], true),
+    unit!("ohm", 1 / 1,     [ 2,  1, -3, -2, 0, 0, 0], true),
+    unit!("L",   1 / 1000,  [ 3,  0,  0,  0, 0, 0, 0], true),
+
+    // Common units which don't take prefixes
+    unit!("min", 60 / 1,    [ 0,  0,  1,  0, 0, 0, 0], false),
+    unit!("h",   3600 / 1,  [ 0,  0,  1,  0, 0, 0, 0], false),
+    unit!("day", 86400 / 1, [ 0,  0,  1,  0, 0, 0, 0], false),
+];
+
+// Decimal prefixes, as (name, power of ten)
+static PREFIXES: [(&str, i32); 10] = [
+    ("T", 12), ("G", 9), ("M", 6), ("k", 3),
+    ("d", -1), ("c", -2), ("m", -3), ("u", -6), ("n", -9), ("p", -12)
+];
+
+// A number together with its dimension
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantity {
+    pub magnitude: Number,
+    pub dimension: Dimension
+}
+
+impl Quantity {
+    // Looks up a unit by name, like `m`, `km` or `ohm`, and returns its value in SI base units
+    pub fn from_unit(name: &str) -> Option<Quantity> {
+        if let Some(unit) = UNITS.iter().find(|u| u.name == name) {
+            return Some(Quantity::from_def(unit, 0));
+        }
+
+        for &(prefix, power) in &PREFIXES {
+            if let Some(rest) = name.strip_prefix(prefix) {
+                if let Some(unit) = UNITS.iter().find(|u| u.prefixable && u.name == rest) {
+                    return Some(Quantity::from_def(unit, power));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn from_def(unit: &UnitDef, power: i32) -> Quantity {
+        let (num, den) = unit.factor;
+        let mut factor = BigRational::new(BigInt::from(num), BigInt::from(den));
+        let prefix = BigRational::from_integer(num_traits::pow(BigInt::from(10), power.unsigned_abs() as usize));
+        if power >= 0 { factor *= prefix } else { factor /= prefix }
+
+        Quantity { magnitude: Number::from_rational(factor), dimension: Dimension(unit.dimension) }
+    }
+
+    pub fn from_value(value: Value) -> CalcResult<Quantity> {
+        match value {
+            Value::Quantity(q) => Ok(q),
+            other              => Ok(Quantity::from(other.into_number()?))
+        }
+    }
+
+    // Dimensionless quantities are turned back into plain numbers
+    pub fn into_value(self, mode: NumericMode) -> Value {
+        let magnitude = mode.apply(self.magnitude);
+        if self.dimension.is_dimensionless() {
+            Value::Number(magnitude)
+        } else {
+            Value::Quantity(Quantity { magnitude, dimension: self.dimension })
+        }
+    }
+
+    pub fn add(&self, other: &Quantity) -> CalcResult<Quantity> {
+        self.check_same_dimension(other, "add")?;
+        Ok(Quantity { magnitude: &self.magnitude + &other.magnitude, dimension: self.dimension })
+    }
+
+    pub fn sub(&self, other: &Quantity) -> CalcResult<Quantity> {
+        self.check_same_dimension(other, "subtract")?;
+        Ok(Quantity { magnitude: &self.magnitude - &other.magnitude, dimension: self.dimension })
+    }
+
+    pub fn mul(&self, other: &Quantity) -> CalcResult<Quantity> {
+        let dimension = self.dimension.add(&other.dimension)?;
+        Ok(Quantity { magnitude: &self.magnitude * &other.magnitude, dimension })
+    }
+
+    // Divides two quantities. The caller must make sure that the divisor is not zero
+    pub fn div(&self, other: &Quantity) -> CalcResult<Quantity> {
+        let dimension = self.dimension.sub(&other.dimension)?;
+        Ok(Quantity { magnitude: self.magnitude.div(&other.magnitude), dimension })
+    }
+
+    pub fn sqrt(&self, mode: NumericMode) -> CalcResult<Quantity> {
+        match self.dimension.halve() {
+            Some(dimension) => Ok(Quantity { magnitude: self.magnitude.sqrt(mode), dimension }),
+            None => Err(CalcError::domain(format!("Cannot take the square root of a quantity with dimension {}", self.dimension)))
+        }
+    }
+
+    // Quantities with a dimension can only be raised to integer powers
+    pub fn pow(&self, exponent: &Number, mode: NumericMode) -> CalcResult<Quantity> {
+        let dimension = if self.dimension.is_dimensionless() {
+            self.dimension
+        } else {
+            match exponent.to_i32().filter(|e| e.abs() <= i8::MAX as i32) {
+                Some(e) => self.dimension.scale(e as i8)?,
+                None    => {
+                    return Err(CalcError::domain(format!("Cannot raise a quantity with dimension {} to the power {}",
+                                       self.dimension, exponent)));
+                }
+            }
+        };
+
+        Ok(Quantity { magnitude: self.magnitude.pow(exponent, mode)?, dimension })
+    }
+
+    // Orders two real quantities with the same dimension
+    pub fn compare(&self, other: &Quantity) -> CalcResult<Ordering> {
+        self.check_comparable(other)?;
+
+        // Only NaN is unordered
+        Ok(self.magnitude.partial_cmp(&other.magnitude).unwrap_or(Ordering::Equal))
+    }
+
+    pub fn check_comparable(&self, other: &Quantity) -> CalcResult<()> {
+        self.check_same_dimension(other, "compare")?;
+        for n in &[&self.magnitude, &other.magnitude] {
+            if n.is_complex() {
+                return Err(CalcError::domain(format!("Cannot compare the non-real number '{}'", n)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_same_dimension(&self, other: &Quantity, action: &str) -> CalcResult<()> {
+        if self.dimension != other.dimension {
+            return Err(CalcError::domain(format!("Cannot {} quantities with dimensions {} and {}",
+                               action, self.dimension, other.dimension)));
+        }
+        Ok(())
+    }
+}
+
+impl From<Number> for Quantity {
+    fn from(magnitude: Number) -> Quantity {
+        Quantity { magnitude, dimension: Dimension::default() }
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.magnitude, f)?;
+        if !self.dimension.is_dimensionless() {
+            write!(f, " {}", self.dimension)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+/*
+
+Implements the values that expressions evaluate to.
+
+*/
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::CalcResult;
+use super::error::CalcError;
+use super::number::Number;
+use super::units::Quantity;
+use super::matrix::Matrix;
+use super::function::Callable;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(Number),
+    Boolean(bool),
+    Str(String),
+    Quantity(Quantity),
+    List(Vec<Value>),
+    Matrix(Matrix),
+    Function(Callable)
+}
+
+impl Value {
+    // The name of the kind of value, used in error messages
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Value::Number(_)   => "number",
+            Value::Boolean(_)  => "boolean",
+            Value::Str(_)      => "string",
+            Value::Quantity(_) => "quantity",
+            Value::List(_)     => "list",
+            Value::Matrix(_)   => "matrix",
+            Value::Function(_) => "function"
+        }
+    }
+
+    pub fn into_number(self) -> CalcResult<Number> {
+        match self {
+            Value::Number(x) => Ok(x),
+            _                => Err(CalcError::domain(format!("Expected a number, found {} '{}'", self.kind(), self)))
+        }
+    }
+
+    pub fn into_str(self) -> CalcResult<String> {
+        match self {
+            Value::Str(s) => Ok(s),
+            _             => Err(CalcError::domain(format!("Expected a string, found {} '{}'", self.kind(), self)))
+        }
+    }
+
+    pub fn into_list(self) -> CalcResult<Vec<Value>> {
+        match self {
+            Value::List(xs) => Ok(xs),
+            _               => Err(CalcError::domain(format!("Expected a list, found {} '{}'", self.kind(), self)))
+        }
+    }
+
+    pub fn into_matrix(self) -> CalcResult<Matrix> {
+        match self {
+            Value::Matrix(m) => Ok(m),
+            _                => Err(CalcError::domain(format!("Expected a matrix, found {} '{}'", self.kind(), self)))
+        }
+    }
+
+    pub fn into_callable(self) -> CalcResult<Callable> {
+        match self {
+            Value::Function(f) => Ok(f),
+            _                  => Err(CalcError::domain(format!("Expected a function, found {} '{}'", self.kind(), self)))
+        }
+    }
+
+    pub fn as_bool(&self) -> CalcResult<bool> {
+        match *self {
+            Value::Boolean(b) => Ok(b),
+            _                 => Err(CalcError::domain(format!("Expected a boolean, found {} '{}'", self.kind(), self)))
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // Forward the formatter, so flags like the decimal view reach the number
+            Value::Number(ref x)   => fmt::Display::fmt(x, f),
+            Value::Boolean(b)      => write!(f, "{}", b),
+            // Strings are printed without quotes, so they can be used as labels
+            Value::Str(ref s)      => f.pad(s),
+            Value::Quantity(ref q) => fmt::Display::fmt(q, f),
+            Value::Matrix(ref m)   => fmt::Display::fmt(m, f),
+            Value::Function(ref c) => write!(f, "{}", c),
+            Value::List(ref xs)    => {
+                write!(f, "(")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    // Inside lists, strings are quoted to tell where they end
+                    match *x {
+                        Value::Str(ref s) => write!(f, "{:?}", s)?,
+                        _                 => fmt::Display::fmt(x, f)?
+                    }
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// Allows comparing results directly with floats, as in `eval("(+ 2 5)").unwrap() == 7.`
+impl PartialEq<f64> for Value {
+    fn eq(&self, other: &f64) -> bool {
+        match *self {
+            Value::Number(ref x) => x.to_f64() == *other,
+            _                    => false
+        }
+    }
+}
+
+impl PartialOrd<f64> for Value {
+    fn partial_cmp(&self, other: &f64) -> Option<Ordering> {
+        match *self {
+            Value::Number(ref x) => x.to_f64().partial_cmp(other),
+            _                    => None
+        }
+    }
+}
@@ -0,0 +1,192 @@
+/*
+
+Implements the stack machine that runs the bytecode produced by the compiler module.
+
+It gives the same results and errors as evaluating the expressions directly. Names are
+resolved in the same order: user-defined variables and functions come first, and the builtin
+meaning found by the parser is only used when there are none.
+
+*/
+
+use std::iter;
+
+use super::CalcResult;
+use super::compiler::{CallSite, Chunk, Guard, Op, Target};
+use super::environment::{Environment, Scope};
+use super::expression;
+use super::function::{is_true, Callable};
+use super::matrix::Matrix;
+use super::number::Number;
+use super::suggest;
+use super::value::Value;
+
+// Chooses how expressions are evaluated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Evaluator {
+    Tree,       // Walking the expressions recursively
+    Bytecode    // Compiling them and running the bytecode
+}
+
+// Runs a chunk, where `locals` are the arguments of the function it was compiled from
+pub fn execute(chunk: &Chunk, env: &Environment, locals: &[Value]) -> CalcResult {
+    let mode = env.numeric_mode();
+    let mut stack = Vec::with_capacity(chunk.max_stack);
+    let mut pc = 0;
+
+    while let Some(op) = chunk.code.get(pc) {
+        pc += 1;
+        match *op {
+            Op::Number(i) if mode == chunk.mode => stack.push(chunk.numbers[i].1.clone()),
+            Op::Number(i) => stack.push(Value::Number(mode.apply(chunk.numbers[i].0.clone()))),
+            Op::Value(i)  => stack.push(chunk.values[i].clone()),
+            Op::Folded(i, end) => {
+                let (ref value, folded_mode, guard) = chunk.folded[i];
+                if folded_mode == mode && holds(&chunk.guards[guard], env) {
+                    stack.push(value.clone());
+                    pc = end;
+                }
+            }
+            Op::Closure(i) => stack.push(Value::Function(Callable::User(chunk.functions[i].clone(), env.scope()))),
+            Op::Local(slot) => stack.push(locals[slot].clone()),
+            Op::Set(name) => {
+                env.set_var(name, stack.pop().unwrap());
+                stack.push(Value::Number(Number::from(0)));
+            }
+            Op::Define(name) => {
+                env.define_var(name, stack.pop().unwrap());
+                stack.push(Value::Number(Number::from(0)));
+            }
+            Op::Scope(i) => stack.push(execute(&chunk.scopes[i], &env.enter_scope(), locals)?),
+            Op::Pop => { stack.pop(); }
+            Op::SkipElse(guard, target) if holds(&chunk.guards[guard], env) => pc = target,
+            Op::SkipElse(..) => (),
+            Op::Jump(end) => pc = end,
+            Op::Branch(..) | Op::ShortCircuit(..) | Op::Test(_) | Op::Match(_) | Op::NoClause | Op::NoMatch => {
+                if let Some(target) = control(op, chunk, env, &mut stack).map_err(|e| e.at(chunk.spans[pc - 1]))? {
+                    pc = target;
+                }
+            }
+            Op::CallIfRedefined(guard, _) if holds(&chunk.guards[guard], env) => (),
+            _ => {
+                // Errors get the span of the expression the instruction comes from
+                let value = apply(op, chunk, env, &mut stack).map_err(|e| e.at(chunk.spans[pc - 1]))?;
+                stack.push(value);
+            }
+        }
+    }
+
+    Ok(stack.pop().unwrap())
+}
+
+// Runs the instructions that compute a value out of the ones on top of the stack, which they
+// take off it. These and the ones of `control` can fail, and are kept apart so recursive calls
+// use less stack
+fn apply(op: &Op, chunk: &Chunk, env: &Environment, stack: &mut Vec<Value>) -> CalcResult {
+    match *op {
+        Op::Name(i) => {
+            let (name, ref builtin) = chunk.names[i];
+            expression::load(name, builtin, env)
+        }
+        Op::Operator(op, n) => {
+            let start = stack.len() - n;
+            op.apply(stack.drain(start..), env)
+        }
+        Op::Call(site, n) => call(&chunk.calls[site], n, env, stack),
+        Op::CallIfRedefined(_, site) => call(&chunk.calls[site], 3, env, stack),
+        Op::Matrix(cols, n) => {
+            let start = stack.len() - n;
+            let elements = stack.drain(start..)
+                                .map(Value::into_number)
+                                .collect::<CalcResult<Vec<_>>>()?;
+            Ok(Value::Matrix(Matrix::new(n / cols, cols, elements)))
+        }
+        _ => unreachable!()
+    }
+}
+
+// Runs the instructions that choose where to continue, depending on the values on top of the
+// stack. Returns the target if they jump
+fn control(op: &Op, chunk: &Chunk, env: &Environment, stack: &mut Vec<Value>) -> CalcResult<Option<usize>> {
+    let (jumps, target) = match *op {
+        Op::Branch(guard, _) if !holds(&chunk.guards[guard], env) => return Ok(None),
+        Op::Branch(_, target) | Op::Test(target) => (!is_true(stack.pop().unwrap(), env)?, target),
+        Op::ShortCircuit(op, end) => {
+            // An argument of `and` or `or` that settles the result is its value
+            let result = op.decides(stack.pop().unwrap(), env)?;
+            if let Some(result) = result {
+                stack.push(Value::Boolean(result));
+            }
+            (result.is_some(), end)
+        }
+        Op::Match(target) => {
+            // The key is only needed until a clause is selected
+            let datum = stack.pop().unwrap();
+            let matches = *stack.last().unwrap() == datum;
+            if matches {
+                stack.pop();
+            }
+            (!matches, target)
+        }
+        Op::NoClause => return Err(expression::no_clause(None)),
+        Op::NoMatch  => return Err(expression::no_clause(stack.last())),
+        _ => unreachable!()
+    };
+    Ok(if jumps { Some(target) } else { None })
+}
+
+// Checks that the names of a guard keep their builtin meaning
+fn holds(guard: &Guard, env: &Environment) -> bool {
+    if guard.names.iter().any(|&name| env.has_local(name)) {
+        return false;
+    }
+
+    let version = env.version();
+    if guard.checked.get() == Some(version) {
+        return true;
+    }
+    let free = !guard.names.iter().any(|&name| env.is_global(name));
+    if free {
+        guard.checked.set(Some(version));
+    }
+    free
+}
+
+// Follows the same order as the evaluation of a call, with the arguments on top of the stack.
+// User functions read them from there, and builtins take them off it
+fn call(site: &CallSite, n: usize, env: &Environment, stack: &mut Vec<Value>) -> CalcResult {
+    let start = stack.len() - n;
+    if let Some(v) = env.get_local(site.name) {
+        return v.into_callable()?.call(stack.split_off(start), env);
+    }
+
+    match target(site, env) {
+        Target::User(f) => {
+            let result = f.call(&Scope::default(), &stack[start..], env);
+            stack.truncate(start);
+            result
+        }
+        Target::Variable => env.get_global(site.name).unwrap().into_callable()?.call(stack.split_off(start), env),
+        Target::Builtin  => match site.builtin {
+            Some(f) => f.apply(stack.drain(start..), env),
+            None    => Err(suggest::unknown_name(site.name.as_str(), env, iter::empty()))
+        }
+    }
+}
+
+// Finds what the name of a call site means at the top level, or takes it from the cache
+fn target(site: &CallSite, env: &Environment) -> Target {
+    let version = env.version();
+    if let Some((checked, ref target)) = *site.target.borrow() {
+        if checked == version {
+            return target.clone();
+        }
+    }
+
+    let target = match env.get_fn(site.name) {
+        Some(f) => Target::User(f),
+        None if env.get_global(site.name).is_some() => Target::Variable,
+        None => Target::Builtin
+    };
+    *site.target.borrow_mut() = Some((version, target.clone()));
+    target
+}
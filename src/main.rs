@@ -1,5 +1,14 @@
+extern crate bigdecimal;
+extern crate num_bigint;
+extern crate num_complex;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
+
+#[cfg(not(test))]
+use std::env;
 #[cfg(not(test))]
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 
 mod calc;
 
@@ -9,25 +18,96 @@ fn main() {
     let stdin_lock = stdin.lock();
     let mut env = calc::Environment::new();
 
+    // Treat numbers as booleans in conditions, like older versions did
+    if env::args().any(|arg| arg == "--compat") {
+        env.set_compatibility_mode(true);
+    }
+
+    // Evaluate expressions by walking them instead of compiling them to bytecode
+    if env::args().any(|arg| arg == "--tree-walker") {
+        env.set_evaluator(calc::Evaluator::Tree);
+    }
+
+    // Print exact fractions as decimals
+    let decimal_view = env::args().any(|arg| arg == "--decimal-view");
+
+    // Use decimal arithmetic with the given number of significant digits
+    for arg in env::args() {
+        if let Some(digits) = arg.strip_prefix("--decimal=") {
+            match digits.parse() {
+                Ok(digits) => env.set_numeric_mode(calc::NumericMode::Decimal(digits)),
+                Err(_) => {
+                    println!("Error: invalid number of digits '{}'", digits);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Lines are accumulated until their parentheses are balanced
+    let mut input = String::new();
     for line in stdin_lock.lines().map(|l| l.unwrap_or_else(|_| String::new())) {
-        match calc::run(&line, &mut env) {
-            Err(msg) => println!("Error: {}", msg),
-            Ok(result) => println!("Result: {}", result)
+        input.push_str(&line);
+        input.push('\n');
+        if calc::is_incomplete(&input) {
+            print!("... ");
+            let _ = io::stdout().flush();
+            continue;
+        }
+
+        print_result(&input, &mut env, decimal_view);
+        input.clear();
+    }
+
+    // Report the missing parentheses of unfinished input
+    if !input.is_empty() {
+        print_result(&input, &mut env, decimal_view);
+    }
+}
+
+// Errors are followed by the line of the input that caused them, with the problem underlined.
+// All problems found before evaluation are reported at once, so they can be fixed together
+#[cfg(not(test))]
+fn print_result(input: &str, env: &mut calc::Environment, decimal_view: bool) {
+    match calc::run(input, env) {
+        Err(e) => {
+            let problems = calc::check(input, env);
+            if problems.contains(&e) {
+                for problem in &problems {
+                    print_error(problem, input);
+                }
+            } else {
+                print_error(&e, input);
+            }
         }
+        Ok(ref result) if decimal_view => println!("Result: {:#}", result),
+        Ok(result) => println!("Result: {}", result)
+    }
+}
+
+#[cfg(not(test))]
+fn print_error(e: &calc::CalcError, input: &str) {
+    println!("Error: {}", e);
+    if let Some(diagnostic) = e.diagnostic(input) {
+        println!("{}", diagnostic);
     }
 }
 
 // General tests
 #[cfg(test)]
 mod tests {
-    use super::calc::{eval, run, Environment};
+    use std::num::NonZeroU64;
+
+    use std::time::{Duration, Instant};
+    use super::calc::{check, compile, eval, is_incomplete, parse_all, run, Arity, CalcError, Environment, Evaluator,
+                      LexicalKind, NumericMode, Value};
 
     #[test]
     fn check_invalid() {
         // Slice of invalid expressions
-        let invalid = ["", "()", "(-)", "(2)", "2",
+        let invalid = ["", "()", "(-)", "(2)", "(+ 2 3))",
             "(+ + 2)", ")+ 2)", "@", "(+ 2 (+ 2 3)",
-            "(+ aaaa 12)"];
+            "(+ aaaa 12)", "(+ 2 3) +", "(set a 1 2)"];
 
         assert!(invalid.iter().map(|&s| eval(s)).all(|x| x.is_err()));
     }
@@ -42,6 +122,203 @@ mod tests {
         assert!(eval("(sqrt 16)").unwrap() == 4.);
     }
 
+    #[test]
+    fn check_program() {
+        // Bare atoms are valid programs
+        assert!(eval("2").unwrap() == 2.);
+        assert!(eval("pi").unwrap() == std::f64::consts::PI);
+
+        // Forms are evaluated in order, and the last result is returned
+        assert!(eval("(set a 1) (set b 2) (+ a b)").unwrap() == 3.);
+        let mut env = Environment::new();
+        run("(set myVar 42) (def (twice x) (* 2 x))", &mut env).unwrap();
+        assert!(run("myVar", &mut env).unwrap() == 42.);
+        assert!(run("(twice myVar)", &mut env).unwrap() == 84.);
+
+        // Nothing is evaluated when a later form is malformed
+        let mut env = Environment::new();
+        assert!(run("(set a 1) (+ a 2))", &mut env).is_err());
+        assert!(run("a", &mut env).is_err());
+    }
+
+    #[test]
+    fn check_incomplete() {
+        assert!(is_incomplete("(def (square x)"));
+        assert!(is_incomplete("(+ 1 (* 2\n 3)"));
+        assert!(!is_incomplete("(+ 1 (* 2\n 3))"));
+        assert!(!is_incomplete("2"));
+
+        // Errors are complete input, so they are reported right away
+        assert!(!is_incomplete("(+ 1 2))"));
+        assert!(!is_incomplete("(+ 1 @"));
+        assert!(!is_incomplete(")("));
+
+        // Parentheses inside strings don't count
+        assert!(!is_incomplete("(+ \"(\" \"a\")"));
+        assert!(eval("(def (square x)\n  (* x x))\n(square 3)").unwrap() == 9.);
+    }
+
+    #[test]
+    fn check_comments() {
+        assert!(eval("(+ 1 2) ; the sum (of two numbers").unwrap() == 3.);
+        assert!(eval("; a whole line\n(* 2 3);trailing").unwrap() == 6.);
+        assert!(eval("(+ 1 #| two #| nested |# |# 2)").unwrap() == 3.);
+        assert!(eval("#|\n multi-line (comment\n|# (- 5 1)").unwrap() == 4.);
+        assert_eq!(eval("(+ \"a;b\" \"#|\")").unwrap().to_string(), "a;b#|");
+        assert!(!is_incomplete("(+ 1 2) ; (unbalanced"));
+        assert!(is_incomplete("#| a\n"));
+        assert!(is_incomplete("(format \"a\n"));
+        assert!(!is_incomplete("#| a\n |# (+ 1 2)\n"));
+
+        assert!(matches!(eval("(+ 1 2) #| unterminated"), Err(CalcError::Lexical { kind: LexicalKind::Unterminated, .. })));
+        assert!(matches!(eval("(+ 1 2) #"), Err(CalcError::Lexical { kind: LexicalKind::Malformed, .. })));
+        assert!(!is_incomplete("(+ 1 \"\\q"));
+        assert!(eval("; only a comment").is_err());
+    }
+
+    #[test]
+    fn check_error_kinds() {
+        assert!(matches!(eval("(+ 1 @)"), Err(CalcError::Lexical { .. })));
+        assert!(matches!(eval("(+ 1 \"abc)"), Err(CalcError::Lexical { .. })));
+        assert!(matches!(eval("(+ 1 2))"), Err(CalcError::Syntax { .. })));
+        assert!(matches!(eval("(lambda (x x) x)"), Err(CalcError::Syntax { .. })));
+        assert!(matches!(eval("(+ 1 aaaa)"), Err(CalcError::UnknownName { ref name, .. }) if name == "aaaa"));
+        assert!(matches!(eval("(+ \"a\" 1)"), Err(CalcError::Domain { .. })));
+        assert!(matches!(eval("(/ 1 (- 2 2))"), Err(CalcError::DivisionByZero { .. })));
+        assert!(matches!(eval("(range 10000000)"), Err(CalcError::LimitExceeded { .. })));
+
+        match eval("(pow 2)") {
+            Err(CalcError::Arity { name, expected, actual, .. }) => {
+                assert_eq!((name.as_str(), expected, actual), ("pow", Arity::Exactly(2), 1));
+            }
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert_eq!(eval("(reduce 1)").unwrap_err().to_string(), "'reduce' requires 2 to 3 arguments, but 1 was given");
+        assert_eq!(eval("(==)").unwrap_err().to_string(), "'==' requires at least 2 arguments, but 0 were given");
+
+        // Recursion is limited instead of overflowing the stack
+        let mut env = Environment::new();
+        run("(def (fact n) (if (== n 0) 1 (* n (fact (- n 1)))))", &mut env).unwrap();
+        assert_eq!(run("(fact 25)", &mut env).unwrap().to_string(), "15511210043330985984000000");
+        run("(def (loop x) (loop x))", &mut env).unwrap();
+        assert!(matches!(run("(loop 1)", &mut env), Err(CalcError::LimitExceeded { .. })));
+        assert!(eval("(+ 1 aaaa)").unwrap_err().span().is_some());
+    }
+
+    #[test]
+    fn check_recovery() {
+        // Every syntax error is reported, and parsing resumes after the malformed expression
+        let source = "(+ (- 2 +) (* 3 4) (/ 1 ())) (set 1 2) (sqrt 4) )";
+        let (program, errors) = parse_all(source);
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0].to_string(), "Operator '+' in wrong position");
+        assert_eq!(errors[1].to_string(), "Invalid first token");
+        assert_eq!(errors[3].to_string(), "Unexpected ')' without a matching '('");
+        assert_eq!(errors[0].span().map(|s| s.column), Some(9));
+        assert_eq!(errors[1].span().map(|s| s.column), Some(26));
+
+        // The forms without errors are kept
+        assert_eq!(program.len(), 2);
+        assert!(errors.iter().all(|e| matches!(e, CalcError::Syntax { .. })));
+
+        // Input that is never closed gives a single error
+        assert_eq!(parse_all("(+ 1 (* 2 (- 3").1.len(), 1);
+        assert!(parse_all("(+ 1 2) (sqrt 4)").1.is_empty());
+        assert!(matches!(parse_all("(+ 1 @)").1[..], [CalcError::Lexical { .. }]));
+
+        // Evaluation still stops at the first error
+        assert_eq!(eval(source).unwrap_err().to_string(), "Operator '+' in wrong position");
+    }
+
+    #[test]
+    fn check_analysis() {
+        // Every arity error and unknown name is found before anything is evaluated
+        let mut env = Environment::new();
+        let source = "(set a 1) (+ (sqrt 1 2) b) (f 1) (/ a)";
+        let problems = check(source, &env);
+        assert_eq!(problems.len(), 4);
+        assert_eq!(problems[0].to_string(), "'sqrt' requires 1 argument, but 2 were given");
+        assert_eq!(problems[1].to_string(), "Unknown name 'b'");
+        assert_eq!(problems[2].to_string(), "Unknown name 'f', did you mean 'if'?");
+        assert_eq!(problems[3].span().map(|s| s.column), Some(34));
+
+        // A rejected script has no effect on the environment
+        assert_eq!(run(source, &mut env).unwrap_err(), problems[0]);
+        assert!(check("(+ a 1)", &env).len() == 1);
+
+        // Names can only be used after they are defined, but function bodies can use any of them
+        assert!(check("(+ c 1) (set c 1)", &env).len() == 1);
+        assert!(check("(def (g x) (* x k)) (set k 2) (g (+ k 1))", &env).is_empty());
+        assert!(check("(def (g x) (* x x)) (g 1 2)", &env).len() == 1);
+        assert!(check("(map (lambda (x) (+ x y)) (list 1))", &env).len() == 1);
+
+        // Function bodies can call functions that a later input defines
+        let mut parity = Environment::new();
+        run("(def (ev n) (if (== n 0) true (od (- n 1))))", &mut parity).unwrap();
+        run("(def (od n) (if (== n 0) false (ev (- n 1))))", &mut parity).unwrap();
+        assert!(run("(ev 10)", &mut parity).unwrap() == Value::Boolean(true));
+        assert!(check("(map (lambda (x) (later x)) (list 1))", &parity).is_empty());
+        assert!(check("(later 1)", &parity).len() == 1);
+
+        // Variables holding functions can only be checked when they are called
+        run("(set h sqrt) (def (twice x) (* 2 x))", &mut env).unwrap();
+        assert!(check("(h 1 2)", &env).is_empty());
+        assert!(check("(twice 1 2)", &env).len() == 1);
+        assert!(check("(+ pi (* 2 m) twice)", &env).is_empty());
+    }
+
+    #[test]
+    fn check_suggestions() {
+        let message = |s: &str, env: &mut Environment| run(s, env).unwrap_err().to_string();
+        let mut env = Environment::new();
+
+        // Builtin functions, constants and operators
+        assert_eq!(message("(sqr 16)", &mut env), "Unknown name 'sqr', did you mean 'sqrt'?");
+        assert_eq!(message("(lsit 1 2)", &mut env), "Unknown name 'lsit', did you mean 'list'?");
+        assert_eq!(message("(* 2 pii)", &mut env), "Unknown name 'pii', did you mean 'pi'?");
+        assert_eq!(message("(=< 1 2)", &mut env), "Unrecognized token '=<', did you mean '<='?");
+
+        // User variables and functions, including the ones defined by the same input
+        run("(set total 10) (def (square x) (* x x))", &mut env).unwrap();
+        assert_eq!(message("(+ totl 1)", &mut env), "Unknown name 'totl', did you mean 'total'?");
+        assert_eq!(message("(sqare 2)", &mut env), "Unknown name 'sqare', did you mean 'square'?");
+        assert_eq!(message("(set count 1) (+ cuont 1)", &mut env), "Unknown name 'cuont', did you mean 'count'?");
+        assert_eq!(message("(def (f radius) (* radus 2))", &mut env), "Unknown name 'radus', did you mean 'radius'?");
+
+        // Names that are too different get no suggestion
+        assert_eq!(message("(+ banana 1)", &mut env), "Unknown name 'banana'");
+        assert!(matches!(eval("(sqr 1)"), Err(CalcError::UnknownName { suggestion: Some(ref s), .. }) if s == "sqrt"));
+    }
+
+    #[test]
+    fn check_diagnostics() {
+        // Errors underline the innermost expression that caused them
+        let source = "(+ 1 (/ 2 0))";
+        let diagnostic = eval(source).unwrap_err().diagnostic(source).unwrap();
+        assert_eq!(diagnostic, "1 | (+ 1 (/ 2 0))\n  |      ^^^^^^^");
+
+        let source = "(set a 1)\n(* a\n   b)";
+        let diagnostic = eval(source).unwrap_err().diagnostic(source).unwrap();
+        assert_eq!(diagnostic, "3 |    b)\n  |    ^");
+
+        // Lexical and syntax errors point at the offending token
+        let source = "(+ 1 \"a\\qb\" 2)";
+        assert_eq!(eval(source).unwrap_err().diagnostic(source).unwrap(), "1 | (+ 1 \"a\\qb\" 2)\n  |      ^^^^");
+        let source = "(+ 1 (- 2 +))";
+        assert_eq!(eval(source).unwrap_err().diagnostic(source).unwrap(), "1 | (+ 1 (- 2 +))\n  |           ^");
+
+        // Unclosed forms point at their opening parenthesis
+        let source = "(+ 1 2) (* 3 (+ 4";
+        assert_eq!(eval(source).unwrap_err().diagnostic(source).unwrap(), "1 | (+ 1 2) (* 3 (+ 4\n  |         ^");
+
+        // Errors inside a function body point at the call
+        let mut env = Environment::new();
+        run("(def (f x) (/ x 0))", &mut env).unwrap();
+        let source = "(+ 1 (f 2))";
+        let diagnostic = run(source, &mut env).unwrap_err().diagnostic(source).unwrap();
+        assert_eq!(diagnostic, "1 | (+ 1 (f 2))\n  |      ^^^^^");
+    }
+
     #[test]
     fn check_subexpr() {
         assert!(eval("(+ (+ 2 3) 5)").unwrap() == 10.);
@@ -55,6 +332,354 @@ mod tests {
         assert!(eval("(+ -5 8)").unwrap() == 3.);
     }
 
+    #[test]
+    fn check_big_integers() {
+        assert_eq!(eval("(* 123456789012345678 1000)").unwrap().to_string(), "123456789012345678000");
+        assert_eq!(eval("(- 9007199254740993 1)").unwrap().to_string(), "9007199254740992");
+        assert_eq!(eval("(pow 2 100)").unwrap().to_string(), "1267650600228229401496703205376");
+        assert_eq!(eval("(pow -1 100001)").unwrap().to_string(), "-1");
+        assert!(matches!(eval("(pow 2 100001)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(matches!(eval("(pow (/ 1 2) 100001)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(matches!(eval("(pow (pow 10 100000) 100000)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(matches!(eval("(pow (/ 1 1024) 100000)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(eval("(== (pow 10 100000) (pow 100 50000))").unwrap() == Value::Boolean(true));
+
+        // Floats and inexact functions promote integers to floats
+        assert_eq!(eval("(+ 1 0.5)").unwrap().to_string(), "1.5");
+        assert_eq!(eval("(sqrt 2)").unwrap().to_string(), 2f64.sqrt().to_string());
+        assert!(eval("(== 2 2.0)").unwrap() == Value::Boolean(true));
+    }
+
+    #[test]
+    fn check_rationals() {
+        assert_eq!(eval("(/ 1 3)").unwrap().to_string(), "1/3");
+        assert_eq!(eval("(* (/ 1 3) 3)").unwrap().to_string(), "1");
+        assert_eq!(eval("(+ (/ 1 3) (/ 1 6))").unwrap().to_string(), "1/2");
+        assert_eq!(eval("(- (/ 1 2) 1)").unwrap().to_string(), "-1/2");
+        assert_eq!(eval("(/ -4 6)").unwrap().to_string(), "-2/3");
+        assert_eq!(eval("(pow (/ 2 3) 2)").unwrap().to_string(), "4/9");
+        assert_eq!(eval("(pow 2 -2)").unwrap().to_string(), "1/4");
+        assert!(matches!(eval("(pow 0 -1)"), Err(CalcError::DivisionByZero { .. })));
+        assert!(eval("(< (/ 1 3) (/ 1 2))").unwrap() == Value::Boolean(true));
+
+        // Floats make the result inexact
+        assert_eq!(eval("(+ (/ 1 2) 0.25)").unwrap().to_string(), "0.75");
+
+        // Decimal view
+        assert_eq!(format!("{:#}", eval("(/ 1 4)").unwrap()), "0.25");
+    }
+
+    #[test]
+    fn check_complex() {
+        assert_eq!(eval("(sqrt -4)").unwrap().to_string(), "0+2i");
+        assert_eq!(eval("(+ 1 2i)").unwrap().to_string(), "1+2i");
+        assert_eq!(eval("(- 1 2i)").unwrap().to_string(), "1-2i");
+        assert_eq!(eval("(* 2i 3i)").unwrap().to_string(), "-6");
+        assert_eq!(eval("(* i i)").unwrap().to_string(), "-1");
+        assert_eq!(eval("(/ 2i 2)").unwrap().to_string(), "0+1i");
+        assert_eq!(eval("(sqrt (* 2i 2))").unwrap().to_string(), "1.4142135623730951+1.4142135623730951i");
+        assert_eq!(eval("(pow i 2)").unwrap().to_string(), "-1");
+        assert!(eval("(== (* i i) -1)").unwrap() == Value::Boolean(true));
+
+        // Complex numbers are not ordered
+        assert!(eval("(< i 2)").is_err());
+        assert!(eval("(>= 1 2i)").is_err());
+    }
+
+    #[test]
+    fn check_folding() {
+        let mut env = Environment::new();
+        run("(def (circle r) (* r (* 2 pi) 1)) (def (big) (pow 2 10))", &mut env).unwrap();
+        assert_eq!(run("(circle 1)", &mut env).unwrap().to_string(), "6.283185307179586");
+
+        // Folded values are not used once their names are shadowed, even by later definitions.
+        // Local bindings only shadow them inside their scope
+        run("(def (area pi) (circle 1)) (def (half) (+ (define pi 3) (* 2 pi)))", &mut env).unwrap();
+        assert_eq!(run("(area 3)", &mut env).unwrap().to_string(), "6.283185307179586");
+        assert!(run("(half)", &mut env).unwrap() == 6.0);
+        run("(set pi 3)", &mut env).unwrap();
+        assert!(run("(circle 1)", &mut env).unwrap() == 6.0);
+        assert!(run("(big)", &mut env).unwrap() == 1024.0);
+        assert!(run("(def (pow a b) (* a b)) (big)", &mut env).unwrap() == 20.0);
+
+        // Or when the numeric mode changes
+        let mut env = Environment::new();
+        run("(def (tau) (* 2 pi))", &mut env).unwrap();
+        env.set_numeric_mode(NumericMode::Decimal(NonZeroU64::new(30).unwrap()));
+        assert_eq!(run("(tau)", &mut env).unwrap().to_string(), "6.28318530717958647692528676656");
+
+        // Logical operators are not folded, since they depend on the compatibility mode
+        for evaluator in [Evaluator::Tree, Evaluator::Bytecode].iter() {
+            let mut env = Environment::new();
+            env.set_evaluator(*evaluator);
+            env.set_compatibility_mode(true);
+            run("(def (f) (not 0))", &mut env).unwrap();
+            env.set_compatibility_mode(false);
+            assert!(run("(f)", &mut env).is_err());
+        }
+
+        // Compiled programs remember what names meant, but only for the environment they were run in
+        let program = compile("(sqrt 16)", &Environment::new()).unwrap();
+        assert!(program.run(&mut Environment::new()).unwrap() == 4.0);
+        let mut env = Environment::new();
+        run("(def (sqrt x) x)", &mut env).unwrap();
+        assert!(program.run(&mut env).unwrap() == 16.0);
+
+        // Removing factors of 1 keeps the meaning of the product
+        assert!(eval("(* (list 2 3) 1)").unwrap() == 6.0);
+        assert!(eval("(* \"a\" 1)").is_err());
+
+        // Constant expressions that fail are only reported if they are evaluated
+        assert!(eval("(if false (/ 1 0) 1)").unwrap() == 1.0);
+        assert_eq!(eval("(+ 1 (/ 1 0))").unwrap_err().span().map(|s| s.column), Some(6));
+    }
+
+    #[test]
+    fn check_evaluators_agree() {
+        let programs = [
+            "(+ 1 2)", "(- 10 (* 2 6) 5)", "(- 5)", "(/ 1 3)", "(/ 1.0 3)", "(* 1.5 2)", "(sqrt -4)",
+            "(* (+ 1 2i) i)", "(pow 2 100)", "(pow 2 0.5)", "(== 1 1 1)", "(!= 1 2)", "(>= 2 (/ 4 2))",
+            "(+ (list 1 2 3) 4)", "(- (list))", "(* 2 pi)", "e", "(* (* 2 m) (* 3 m))", "(+ (* 1 m) (* 2 s))",
+            "(/ 1 0)", "(+ 1 (/ 2 (- 1 1)))", "(if (< 1 2) 10 (/ 1 0))", "(if 1 2 3)", "(if (> 1 2) 1)",
+            "(map (lambda (x) (* x x)) (range 1 5))", "(filter (lambda (x) (> x 2)) (list 1 2 3 4))",
+            "(reduce (lambda (a b) (+ a b)) (range 10))", "(sort (list 3 1 2))", "(nth (list 1 2) 5)",
+            "(len (list))", "(sum (range 101))", "(sqrt (list 1))", "(det (matrix (1 2) (3 4)))",
+            "(inv (matrix (1 2) (2 4)))", "(transpose (matrix (1 2 3)))", "(matrix (1 (list 1)))",
+            "(* (matrix (1 2) (3 4)) (matrix (1) (1)))", "(+ \"Total: \" \"42\")", "(format \"{:>8.3}\" pi)",
+            "(* \"a\" 1)", "(set a 5) (set b (* a 2)) (+ a b)",
+            "(def (fact n) (if (== n 0) 1 (* n (fact (- n 1))))) (fact 20)",
+            "(def (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))) (fib 12)",
+            "(def (loop x) (loop x)) (loop 1)", "(def (f x) (/ x 0)) (+ 1 (f 1))",
+            "(def (f x) (if x 1 2)) (f 0)", "(def (f x) (* x k)) (set k 3) (f 2)",
+            "(def (g) (* 2 y)) (def (h y) (g)) (h 21)", "(def (if a b c) (+ a b c)) (if 1 2 3)",
+            "(set if sqrt) (if 1 2 3)", "(set pi 3) (* 2 pi)", "(def (sqrt x) x) (sqrt 16)", "(set f sqrt) (f 16)",
+            "(set sq (lambda (x) (* x x))) (map sq (list 1 2 3))", "(def (twice f x) (f (f x))) (twice sqrt 16)",
+            "(def (scale k xs) (map (lambda (x) (* k x)) xs)) (scale 3 (list 1 2))", "(set x 2) (x 1)",
+            "(def (f pi m) (* pi m)) (f 2 3)", "(def (adder n) (lambda (x) (+ x n))) (map (adder 2) (list 1 2))",
+            "(set c 0) (def (bump) (set c (+ c 1))) (bump) (bump) c", "(def (f x) (+ (set x 5) x)) (f 1)",
+            "(set y 1) (def (f) (+ (define y 10) y)) (+ (f) y)", "(def (g) x) (def (f x) (g)) (f 1)",
+            "(let ((x 2) (y (* x 3))) (+ x y))", "(def (f x) (let ((x (* x 10))) x)) (f 2)", "(begin 1 (/ 1 0) 3)",
+            "(def (mk) (let ((n 0)) (lambda () (begin (set n (+ n 1)) n)))) (set c (mk)) (c) (c)",
+            "(let ((pi 3)) (* 2 pi))", "(and (< 1 2) (or false (> 3 2)))", "(and 1 2)", "(or false 0 (/ 1 0))",
+            "(set x 0) (and (!= x 0) (> (/ 1 x) 2))", "(or (list false) (list true (/ 1 0)))", "(not (xor 1 0))",
+            "(def (f x) (begin (define y (* x 2)) (+ x y))) (f 4)", "(def (f sqrt) (sqrt 4)) (f (lambda (x) x))",
+            "(def (f x y) (- y x)) (f 1 5)",
+            "(cond ((< 2 1) (/ 1 0)) ((> 2 1) 2) (else 3))", "(cond (false 1))", "(cond (1 2))", "(cond ((/ 1 0) 1))",
+            "(def (tax x) (cond ((< x 10) 0) ((< x 20) (* 0.1 x)) (else (* 0.2 x)))) (list (tax 5) (tax 15) (tax 25))",
+            "(case (+ 1 1) (1 10) (2 20) (else (/ 1 0)))", "(case 3 (1 10) (2 20))", "(case \"b\" (\"a\" 1) (\"b\" 2))",
+            "(def (f x) (case x ((- x 1) 1) ((+ x 0) 2))) (f 4)", "(let ((x 2)) (+ 1 (case x (1 10) (else (* x 100)))))"
+        ];
+
+        let modes: [fn(&mut Environment); 3] = [
+            |_| (),
+            |env| env.set_numeric_mode(NumericMode::Decimal(NonZeroU64::new(20).unwrap())),
+            |env| env.set_compatibility_mode(true)
+        ];
+
+        // Values are compared through their text, since lambdas are only equal to themselves
+        let outcome = |result: Result<Value, CalcError>| result.map(|v| v.to_string());
+        for program in programs.iter() {
+            for set_mode in modes.iter() {
+                let mut tree = Environment::new();
+                set_mode(&mut tree);
+                tree.set_evaluator(Evaluator::Tree);
+                let mut bytecode = Environment::new();
+                set_mode(&mut bytecode);
+                assert_eq!(outcome(run(program, &mut tree)), outcome(run(program, &mut bytecode)), "{}", program);
+            }
+        }
+    }
+
+    // Compares the speed of the evaluators on programs that are compiled once and run many times.
+    // Run it with `cargo test --release -- --ignored --nocapture bench_evaluators`. The evaluators
+    // take turns, and the best of several rounds is kept, so noise on a busy machine matters less
+    #[test]
+    #[ignore]
+    fn bench_evaluators() {
+        let setup = "(set x 7.5) (def (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))";
+        let cases = [
+            ("formula", "(+ (* x 2) (/ x 3) (- x 1) (sqrt x) (* 2 pi x) (pow x 2))", 200_000),
+            ("recursion", "(fib 15)", 50),
+            ("lists", "(sum (map (lambda (y) (* y x)) (range 100)))", 5_000)
+        ];
+
+        for &(name, source, runs) in cases.iter() {
+            let mut best = [Duration::MAX; 2];
+            for _ in 0..5 {
+                for (i, &evaluator) in [Evaluator::Tree, Evaluator::Bytecode].iter().enumerate() {
+                    let mut env = Environment::new();
+                    env.set_evaluator(evaluator);
+                    run(setup, &mut env).unwrap();
+                    let program = compile(source, &env).unwrap();
+
+                    let start = Instant::now();
+                    for _ in 0..runs {
+                        program.run(&mut env).unwrap();
+                    }
+                    best[i] = best[i].min(start.elapsed());
+                }
+            }
+            println!("{:<10} tree: {:>8.1?}  bytecode: {:>8.1?}  speedup: {:.2}x",
+                     name, best[0], best[1], best[0].as_secs_f64() / best[1].as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn check_decimal_mode() {
+        let mut env = Environment::new();
+        assert_eq!(run("(+ 0.1 0.2)", &mut env).unwrap().to_string(), "0.30000000000000004");
+
+        env.set_numeric_mode(NumericMode::Decimal(NonZeroU64::new(30).unwrap()));
+        assert_eq!(run("(+ 0.1 0.2)", &mut env).unwrap().to_string(), "0.3");
+        assert!(run("(== (+ 0.1 0.2) 0.3)", &mut env).unwrap() == Value::Boolean(true));
+        assert_eq!(run("(* 0.000001 0.000001)", &mut env).unwrap().to_string(), "0.000000000001");
+        assert_eq!(run("(* 1.1 1.1)", &mut env).unwrap().to_string(), "1.21");
+        assert_eq!(run("(sqrt 2)", &mut env).unwrap().to_string(), "1.41421356237309504880168872421");
+        assert_eq!(run("(pow 2 0.5)", &mut env).unwrap().to_string(), "1.41421356237309504880168872421");
+        assert_eq!(run("pi", &mut env).unwrap().to_string(), "3.14159265358979323846264338328");
+        assert_eq!(run("(+ e 0)", &mut env).unwrap().to_string(), "2.71828182845904523536028747135");
+
+        // Integers and rationals are still exact
+        assert_eq!(run("(* 123456789012345678901234567890 10)", &mut env).unwrap().to_string(),
+                   "1234567890123456789012345678900");
+        assert_eq!(run("(/ 1 3)", &mut env).unwrap().to_string(), "1/3");
+        assert!(run("(== (* 3 (/ 1 3)) 1)", &mut env).unwrap() == Value::Boolean(true));
+        assert_eq!(run("(* 1e-30 1)", &mut env).unwrap().to_string(), "0.000000000000000000000000000001");
+
+        env.set_numeric_mode(NumericMode::Decimal(NonZeroU64::new(5).unwrap()));
+        assert_eq!(run("(* pi 1)", &mut env).unwrap().to_string(), "3.1416");
+        assert_eq!(run("(* pi 20000)", &mut env).unwrap().to_string(), "62832");
+    }
+
+    #[test]
+    fn check_units() {
+        assert_eq!(eval("(* 3 m)").unwrap().to_string(), "3 m");
+        assert_eq!(eval("(/ 10 s)").unwrap().to_string(), "10 s^-1");
+        assert_eq!(eval("(* (* 2 m) (* 3 m))").unwrap().to_string(), "6 m^2");
+        assert_eq!(eval("(+ (* 1 km) (* 500 m))").unwrap().to_string(), "1500 m");
+        assert_eq!(eval("(* 5 g)").unwrap().to_string(), "1/200 kg");
+        assert_eq!(eval("(* 2 N)").unwrap().to_string(), "2 m kg s^-2");
+        assert_eq!(eval("(/ (* 100 km) h)").unwrap().to_string(), "250/9 m s^-1");
+        assert_eq!(eval("(sqrt (* 16 m m))").unwrap().to_string(), "4 m");
+        assert_eq!(eval("(pow (* 2 cm) 3)").unwrap().to_string(), "1/125000 m^3");
+
+        // Dimensions cancel out
+        assert!(eval("(/ (* 6 m) (* 3 m))").unwrap() == 2.);
+        assert!(eval("(== (* 100 cm) m)").unwrap() == Value::Boolean(true));
+        assert!(eval("(< (* 1 mm) (* 1 um))").unwrap() == Value::Boolean(false));
+
+        // Dimension mismatches
+        assert!(eval("(+ (* 1 m) (* 2 s))").is_err());
+        assert!(eval("(+ (* 1 m) 2)").is_err());
+        assert!(eval("(< (* 1 m) 2)").is_err());
+        assert!(eval("(sqrt (* 2 m))").is_err());
+        assert!(eval("(pow m 0.5)").is_err());
+
+        // Unit exponents that don't fit
+        assert!(matches!(eval("(pow (* 1 m m) 100)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(matches!(eval("(* (pow m 127) m)"), Err(CalcError::LimitExceeded { .. })));
+        assert!(matches!(eval("(/ (pow m -127) (* m m))"), Err(CalcError::LimitExceeded { .. })));
+
+        // Variables take precedence over units
+        let mut env = Environment::new();
+        run("(set m 5)", &mut env).unwrap();
+        assert!(run("(* 3 m)", &mut env).unwrap() == 15.);
+    }
+
+    #[test]
+    fn check_lists() {
+        assert_eq!(eval("(list 1 2 3)").unwrap().to_string(), "(1 2 3)");
+        assert_eq!(eval("(range 5)").unwrap().to_string(), "(0 1 2 3 4)");
+        assert_eq!(eval("(range 2 8 3)").unwrap().to_string(), "(2 5)");
+        assert_eq!(eval("(range 3 0 -1)").unwrap().to_string(), "(3 2 1)");
+        assert_eq!(eval("(len (range 10))").unwrap().to_string(), "10");
+        assert_eq!(eval("(nth (list 4 5 6) 1)").unwrap().to_string(), "5");
+        assert_eq!(eval("(sort (list 3 1 (/ 1 2) 2))").unwrap().to_string(), "(1/2 1 2 3)");
+        assert_eq!(eval("(sum (range 101))").unwrap().to_string(), "5050");
+
+        // Higher-order functions take the name of a function or a lambda
+        assert_eq!(eval("(map sqrt (list 1 4 9))").unwrap().to_string(), "(1 2 3)");
+        assert_eq!(eval("(map (lambda (x) (* x x)) (range 4))").unwrap().to_string(), "(0 1 4 9)");
+        assert_eq!(eval("(filter (lambda (x) (> x 2)) (range 5))").unwrap().to_string(), "(3 4)");
+        assert_eq!(eval("(reduce (lambda (acc x) (* acc x)) 1 (range 1 6))").unwrap().to_string(), "120");
+        assert_eq!(eval("(reduce pow (list 2 3 2))").unwrap().to_string(), "64");
+
+        let mut env = Environment::new();
+        run("(def (double x) (* 2 x))", &mut env).unwrap();
+        run("(set data (list 1 2 3))", &mut env).unwrap();
+        assert_eq!(run("(map double data)", &mut env).unwrap().to_string(), "(2 4 6)");
+        run("(set square (lambda (x) (* x x)))", &mut env).unwrap();
+        assert_eq!(run("(square 7)", &mut env).unwrap().to_string(), "49");
+
+        // Variadic operators accept lists
+        assert_eq!(eval("(+ (list 1 2 3))").unwrap().to_string(), "6");
+        assert_eq!(eval("(* 2 (list 3 4))").unwrap().to_string(), "24");
+        assert_eq!(eval("(- (list 10 1 2))").unwrap().to_string(), "7");
+
+        // Errors
+        assert!(eval("(nth (list 1 2) 2)").is_err());
+        assert!(eval("(map 1 (list 1 2))").is_err());
+        assert!(eval("(filter (lambda (x) x) (list 1 2))").is_err());
+        assert!(eval("(reduce pow (list))").is_err());
+        assert!(eval("(range 1 5 0)").is_err());
+        assert!(eval("(sort (list 1 i))").is_err());
+        assert!(eval("(len 5)").is_err());
+    }
+
+    #[test]
+    fn check_matrices() {
+        assert_eq!(eval("(matrix (1 2) (3 4))").unwrap().to_string(), "(matrix (1 2) (3 4))");
+        assert_eq!(eval("(+ (matrix (1 2)) (matrix (3 4)))").unwrap().to_string(), "(matrix (4 6))");
+        assert_eq!(eval("(* (matrix (1 2) (3 4)) (matrix (5) (6)))").unwrap().to_string(), "(matrix (17) (39))");
+        assert_eq!(eval("(* 2 (matrix (1 2)))").unwrap().to_string(), "(matrix (2 4))");
+        assert_eq!(eval("(transpose (matrix (1 2 3)))").unwrap().to_string(), "(matrix (1) (2) (3))");
+        assert_eq!(eval("(trace (matrix (1 2) (3 4)))").unwrap().to_string(), "5");
+        assert_eq!(eval("(rank (matrix (1 2) (2 4)))").unwrap().to_string(), "1");
+
+        // Elimination is exact for exact numbers
+        assert_eq!(eval("(det (matrix (1 2) (3 4)))").unwrap().to_string(), "-2");
+        assert_eq!(eval("(det (matrix (0 1) (1 0)))").unwrap().to_string(), "-1");
+        assert_eq!(eval("(inv (matrix (1 2) (3 4)))").unwrap().to_string(), "(matrix (-2 1) (3/2 -1/2))");
+        assert_eq!(eval("(solve (matrix (2 1) (1 3)) (list 3 5))").unwrap().to_string(), "(4/5 7/5)");
+        assert_eq!(eval("(== (* (matrix (1 2) (3 4)) (inv (matrix (1 2) (3 4)))) (matrix (1 0) (0 1)))").unwrap(),
+                   Value::Boolean(true));
+
+        // Errors name the shapes involved
+        assert_eq!(eval("(+ (matrix (1 2)) (matrix (1) (2)))").unwrap_err().to_string(), "Cannot add matrices of shapes 1x2 and 2x1");
+        assert_eq!(eval("(* (matrix (1 2)) (matrix (1 2)))").unwrap_err().to_string(), "Cannot multiply matrices of shapes 1x2 and 1x2");
+        assert_eq!(eval("(det (matrix (1 2 3)))").unwrap_err().to_string(), "'det' requires a square matrix, found 1x3");
+        assert!(eval("(inv (matrix (1 2) (2 4)))").is_err());
+        assert!(eval("(matrix (1 2) (3))").is_err());
+        assert!(eval("(+ 1 (matrix (1)))").is_err());
+    }
+
+    #[test]
+    fn check_strings() {
+        assert_eq!(eval(r#"(+ "Total: " "42")"#).unwrap(), Value::Str("Total: 42".to_string()));
+        assert_eq!(eval(r#"(+ "a\tb" "\"\\\u{e9}")"#).unwrap().to_string(), "a\tb\"\\\u{e9}");
+        assert_eq!(eval(r#"(list "a b" 1)"#).unwrap().to_string(), r#"("a b" 1)"#);
+        assert_eq!(eval(r#"(== "abc" "abc")"#).unwrap(), Value::Boolean(true));
+
+        assert_eq!(eval(r#"(format "Total: {} EUR" 42)"#).unwrap().to_string(), "Total: 42 EUR");
+        assert_eq!(eval(r#"(format "{:.2} {:.0} {:.3}" (/ 10 3) (/ 5 2) 1.5)"#).unwrap().to_string(), "3.33 2 1.500");
+        assert_eq!(eval(r#"(format "[{:5}] [{:5}] [{:^5}] [{:<5.1}]" 42 "ab" "x" pi)"#).unwrap().to_string(),
+                   "[   42] [ab   ] [  x  ] [3.1  ]");
+        assert_eq!(eval(r#"(format "{{{}}}" (* 2 km))"#).unwrap().to_string(), "{2000 m}");
+
+        assert!(eval(r#"(+ "abc)"#).is_err());
+        assert!(eval(r#"(+ "\q")"#).is_err());
+        assert!(eval(r#"(+ "a" 1)"#).is_err());
+        assert!(eval(r#"(format "{} {}" 1)"#).is_err());
+        assert!(eval(r#"(format "{}" 1 2)"#).is_err());
+        assert!(eval(r#"(format "{:x}" 1)"#).is_err());
+        assert!(eval(r#"(format "{:.65536}" 1)"#).is_err());
+        assert!(eval(r#"(format "{:99999999999999999}" 1)"#).is_err());
+        assert!(eval(r#"(format "{:18446744073709551615}" 1)"#).is_err());
+        assert!(eval(r#"(format "Total: {" 1)"#).is_err());
+        assert!(eval(r#"(format "x{:5" 1)"#).is_err());
+        assert_eq!(eval(r#"(if false (format "{:.70000}" 1) 0)"#).unwrap().to_string(), "0");
+    }
+
     #[test]
     fn check_constants() {
         assert!(eval("(* pi 2)").unwrap() > 6.);
@@ -62,6 +687,141 @@ mod tests {
         assert!(eval("(* 2 e)").unwrap() > 4.);
     }
 
+    #[test]
+    fn check_booleans() {
+        assert!(eval("(< 2 3)").unwrap() == Value::Boolean(true));
+        assert!(eval("(>= 2 3)").unwrap() == Value::Boolean(false));
+        assert!(eval("(== 2 2 2)").unwrap() == Value::Boolean(true));
+        assert!(eval("(!= 2 2)").unwrap() == Value::Boolean(false));
+        assert!(eval("(if (== 2 2) (+ 5 8) (- 5 8))").unwrap() == 13.);
+        assert!(eval("(if false 1 2)").unwrap() == 2.);
+
+        // Booleans are not numbers and numbers are not booleans
+        assert!(eval("(+ true 1)").is_err());
+        assert!(eval("(if 1 2 3)").is_err());
+
+        // Unless compatibility mode is on
+        let mut env = Environment::new();
+        env.set_compatibility_mode(true);
+        assert!(run("(if 1 2 3)", &mut env).unwrap() == 2.);
+        assert!(run("(if 0 2 3)", &mut env).unwrap() == 3.);
+        assert!(run("(and 1 (not 0))", &mut env).unwrap() == Value::Boolean(true));
+    }
+
+    #[test]
+    fn check_logic() {
+        assert!(eval("(and true (< 1 2) (not false))").unwrap() == Value::Boolean(true));
+        assert!(eval("(or false (> 1 2))").unwrap() == Value::Boolean(false));
+        assert!(eval("(xor true false)").unwrap() == Value::Boolean(true));
+        assert!(eval("(xor true true)").unwrap() == Value::Boolean(false));
+        assert!(eval("(and)").unwrap() == Value::Boolean(true));
+        assert!(eval("(or)").unwrap() == Value::Boolean(false));
+        assert!(eval("(and (list true false))").unwrap() == Value::Boolean(false));
+
+        // `and` and `or` stop at the first argument that settles the result
+        let mut env = Environment::new();
+        run("(set x 0) (def (guarded x) (and (!= x 0) (> (/ 1 x) 2)))", &mut env).unwrap();
+        assert!(run("(guarded x)", &mut env).unwrap() == Value::Boolean(false));
+        assert!(run("(guarded 0.25)", &mut env).unwrap() == Value::Boolean(true));
+        assert!(eval("(or (== 1 1) (/ 1 0))").unwrap() == Value::Boolean(true));
+        assert!(eval("(and true (/ 1 0))").is_err());
+
+        // The arguments must be booleans
+        assert_eq!(eval("(and true 1)").unwrap_err().to_string(), "Expected a boolean, found number '1'");
+        assert!(eval("(not 1 2)").is_err());
+        assert!(eval("(xor true)").is_err());
+    }
+
+    #[test]
+    fn check_conditionals() {
+        let mut env = Environment::new();
+        run("(def (tax x) (cond ((< x 10) 0) ((< x 20) (* 0.1 x)) (else (* 0.2 x))))", &mut env).unwrap();
+        assert!(run("(tax 5)", &mut env).unwrap() == 0.0);
+        assert!(run("(tax 15)", &mut env).unwrap() == 1.5);
+        assert!(run("(tax 50)", &mut env).unwrap() == 10.0);
+        assert!(eval("(case (* 2 3) (5 \"five\") (6 \"six\") (else \"other\"))").unwrap() == Value::Str("six".to_string()));
+
+        // Only the selected clause is evaluated, and the tests after it are not
+        assert!(eval("(cond ((> 2 1) 1) ((/ 1 0) 2))").unwrap() == 1.0);
+        assert!(eval("(case 1 (1 2) ((/ 1 0) 3) (else (/ 1 0)))").unwrap() == 2.0);
+
+        // Without an else clause, it is an error if no clause is selected
+        assert_eq!(eval("(cond ((< 2 1) 1))").unwrap_err().to_string(), "No clause of the cond has a true test");
+        assert_eq!(eval("(case 3 (1 10) (2 20))").unwrap_err().to_string(), "No clause of the case matches the value 3");
+        assert!(eval("(cond)").is_err());
+        assert!(eval("(cond (else 1) (true 2))").is_err());
+        assert!(eval("(+ else 1)").is_err());
+    }
+
+    #[test]
+    fn check_symbols() {
+        let mut env = Environment::new();
+
+        // Names keep their text in values and messages
+        run("(def (double x) (* x 2)) (set twice double)", &mut env).unwrap();
+        assert_eq!(run("twice", &mut env).unwrap().to_string(), "<function double>");
+        assert_eq!(eval("(def f (x))").unwrap_err().to_string(), "Unexpected Name(\"f\") expecting LPar");
+
+        // Parameters are read from their slots, even when they shadow builtins or other variables
+        run("(set x 10) (def (sub x e) (- x e))", &mut env).unwrap();
+        assert!(run("(sub 5 3)", &mut env).unwrap() == 2.0);
+        assert!(run("(+ x e)", &mut env).unwrap() == 10.0 + std::f64::consts::E);
+
+        // Functions called from the body don't see its parameters
+        run("(def (get) x) (def (outer x) (get))", &mut env).unwrap();
+        assert!(run("(outer 3)", &mut env).unwrap() == 10.0);
+    }
+
+    #[test]
+    fn check_scopes() {
+        let mut env = Environment::new();
+
+        // Lambdas keep the scope they were created in
+        run("(def (adder n) (lambda (x) (+ x n))) (set add2 (adder 2))", &mut env).unwrap();
+        assert!(run("(add2 5)", &mut env).unwrap() == 7.0);
+        assert_eq!(run("(map (adder 10) (list 1 2))", &mut env).unwrap().to_string(), "(11 12)");
+
+        // `set` updates the nearest binding, creating a global one if there is none
+        run("(set counter 0) (def (bump) (set counter (+ counter 1))) (bump) (bump)", &mut env).unwrap();
+        assert!(run("counter", &mut env).unwrap() == 2.0);
+        run("(def (reset x) (+ (set x 5) x)) (def (fresh) (set created 1)) (fresh)", &mut env).unwrap();
+        assert!(run("(reset 1)", &mut env).unwrap() == 5.0);
+        assert!(run("created", &mut env).unwrap() == 1.0);
+
+        // `define` binds in the current scope, without touching the ones around it
+        run("(define y 1) (def (shadow) (+ (define y 10) y))", &mut env).unwrap();
+        assert!(run("(shadow)", &mut env).unwrap() == 10.0);
+        assert!(run("y", &mut env).unwrap() == 1.0);
+
+        // Local variables hide the user's functions
+        run("(def (sq x) (* x x)) (def (call sq x) (sq x))", &mut env).unwrap();
+        assert!(run("(call sqrt 16)", &mut env).unwrap() == 4.0);
+    }
+
+    #[test]
+    fn check_let() {
+        // Bindings are sequential, and the body gives the value of its last form
+        assert!(eval("(let ((x 2) (y (* x 3))) (+ x y))").unwrap() == 8.0);
+        assert!(eval("(let ((x 1)) (set x 5) (* x 2))").unwrap() == 10.0);
+        assert!(eval("(begin (define a 2) (define b 3) (* a b))").unwrap() == 6.0);
+
+        // The names only exist inside the block, and hide the ones around it
+        let mut env = Environment::new();
+        run("(set x 10) (def (f x) (let ((x (* x 2))) (+ x 1)))", &mut env).unwrap();
+        assert!(run("(+ (let ((x 1)) x) x)", &mut env).unwrap() == 11.0);
+        assert!(run("(f 3)", &mut env).unwrap() == 7.0);
+        assert!(run("(let ((y 1)) y) y", &mut env).is_err());
+
+        // Lambdas created in a block keep its variables alive
+        run("(def (counter) (let ((n 0)) (lambda () (begin (set n (+ n 1)) n)))) (set next (counter))", &mut env).unwrap();
+        run("(next) (next)", &mut env).unwrap();
+        assert!(run("(next)", &mut env).unwrap() == 3.0);
+
+        assert!(eval("(let ((x)) x)").is_err());
+        assert_eq!(eval("(let ((x 1)))").unwrap_err().to_string(), "A let requires a body");
+        assert_eq!(eval("(begin)").unwrap_err().to_string(), "A begin requires at least one form");
+    }
+
     #[test]
     fn check_var() {
         let mut env = Environment::new();
@@ -80,5 +840,21 @@ mod tests {
 
         run("(def (myFunc arg1 arg2 arg3) (+ arg1 arg2 arg3))", &mut env).unwrap();
         assert!(run("(myFunc 1 2 3)", &mut env).unwrap() == 6.0);
+
+        // Wrong number of arguments
+        assert!(run("(myFunc 1 2)", &mut env).is_err());
+
+        // Parameters don't leak into the global environment
+        run("(set arg1 10)", &mut env).unwrap();
+        assert!(run("(myFunc 1 2 3)", &mut env).unwrap() == 6.0);
+        assert!(run("(+ arg1 0)", &mut env).unwrap() == 10.0);
+
+        // Recursive functions
+        run("(def (fact n) (if (< n 1) 1 (* n (fact (- n 1)))))", &mut env).unwrap();
+        assert!(run("(fact 5)", &mut env).unwrap() == 120.0);
+
+        // Invalid definitions
+        assert!(run("(def (bad x x) (+ x x))", &mut env).is_err());
+        assert!(run("(def bad (+ 1 2))", &mut env).is_err());
     }
 }